@@ -0,0 +1,65 @@
+use alloc::{rc::Rc, sync::Arc};
+
+use crate::{AllocOptions, DmaBuf, Result};
+
+/// A source of DMA-Bufs
+///
+/// It's implemented by [`crate::Heap`], [`crate::Udmabuf`] and [`crate::HeapChain`], and allows
+/// to write code, such as [`crate::BufferPool`], that works with any of them.
+///
+/// # Example
+///
+/// ```no_run
+/// use dma_heap::{DmaBuf, DmaBufAllocator, Heap, HeapKind, Result, Udmabuf};
+///
+/// fn allocate_frame<A: DmaBufAllocator>(allocator: &A) -> Result<DmaBuf> {
+///     allocator.allocate(1920 * 1080 * 4)
+/// }
+///
+/// let heap = Heap::new(HeapKind::System).unwrap();
+/// let frame = allocate_frame(&heap).unwrap();
+///
+/// let udmabuf = Udmabuf::new().unwrap();
+/// let frame = allocate_frame(&udmabuf).unwrap();
+/// ```
+pub trait DmaBufAllocator {
+    /// Allocates a DMA-Buf with the specified size and options
+    ///
+    /// # Errors
+    ///
+    /// Will return [Error] if the options aren't supported, or if the allocation fails.
+    fn allocate_with(&self, len: usize, options: &AllocOptions) -> Result<DmaBuf>;
+
+    /// Allocates a DMA-Buf with the specified size and the default options
+    ///
+    /// # Errors
+    ///
+    /// Will return [Error] if the allocation fails.
+    fn allocate(&self, len: usize) -> Result<DmaBuf> {
+        self.allocate_with(len, &AllocOptions::default())
+    }
+}
+
+impl<T: DmaBufAllocator + ?Sized> DmaBufAllocator for &T {
+    fn allocate_with(&self, len: usize, options: &AllocOptions) -> Result<DmaBuf> {
+        (**self).allocate_with(len, options)
+    }
+}
+
+impl<T: DmaBufAllocator + ?Sized> DmaBufAllocator for Box<T> {
+    fn allocate_with(&self, len: usize, options: &AllocOptions) -> Result<DmaBuf> {
+        (**self).allocate_with(len, options)
+    }
+}
+
+impl<T: DmaBufAllocator + ?Sized> DmaBufAllocator for Rc<T> {
+    fn allocate_with(&self, len: usize, options: &AllocOptions) -> Result<DmaBuf> {
+        (**self).allocate_with(len, options)
+    }
+}
+
+impl<T: DmaBufAllocator + ?Sized> DmaBufAllocator for Arc<T> {
+    fn allocate_with(&self, len: usize, options: &AllocOptions) -> Result<DmaBuf> {
+        (**self).allocate_with(len, options)
+    }
+}
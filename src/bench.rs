@@ -0,0 +1,299 @@
+use core::{hint::black_box, time::Duration};
+use std::time::Instant;
+
+use log::debug;
+use rustix::param::page_size;
+
+use crate::{DmaBuf, DmaBufAllocator, Result};
+
+/// Number of iterations run for each size by default
+const DEFAULT_ITERATIONS: usize = 10;
+
+/// Sizes benchmarked by default, from 4 KiB to 256 MiB
+const DEFAULT_SIZES: [usize; 9] = [
+    4 << 10,
+    16 << 10,
+    64 << 10,
+    256 << 10,
+    1 << 20,
+    4 << 20,
+    16 << 20,
+    64 << 20,
+    256 << 20,
+];
+
+/// Distribution of the durations measured over the iterations of a [`Benchmark`]
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct Percentiles {
+    min: Duration,
+    p50: Duration,
+    p90: Duration,
+    p99: Duration,
+    max: Duration,
+}
+
+impl Percentiles {
+    fn from_samples(mut samples: Vec<Duration>) -> Self {
+        samples.sort_unstable();
+
+        // Nearest-rank method
+        let percentile = |p: usize| {
+            let rank = (samples.len() * p).div_ceil(100).max(1);
+
+            samples.get(rank - 1).copied().unwrap_or_default()
+        };
+
+        Self {
+            min: samples.first().copied().unwrap_or_default(),
+            p50: percentile(50),
+            p90: percentile(90),
+            p99: percentile(99),
+            max: samples.last().copied().unwrap_or_default(),
+        }
+    }
+
+    /// Returns the shortest duration measured
+    #[must_use]
+    pub fn min(&self) -> Duration {
+        self.min
+    }
+
+    /// Returns the median duration
+    #[must_use]
+    pub fn p50(&self) -> Duration {
+        self.p50
+    }
+
+    /// Returns the 90th percentile of the durations
+    #[must_use]
+    pub fn p90(&self) -> Duration {
+        self.p90
+    }
+
+    /// Returns the 99th percentile of the durations
+    #[must_use]
+    pub fn p99(&self) -> Duration {
+        self.p99
+    }
+
+    /// Returns the longest duration measured
+    #[must_use]
+    pub fn max(&self) -> Duration {
+        self.max
+    }
+}
+
+/// Results of a [`Benchmark`] for a given buffer size
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct BenchmarkResult {
+    size: usize,
+    allocation: Percentiles,
+    mmap: Percentiles,
+    first_touch: Percentiles,
+    write: Percentiles,
+    read: Percentiles,
+}
+
+impl BenchmarkResult {
+    /// Returns the size of the buffers, in bytes
+    #[must_use]
+    pub fn size(&self) -> usize {
+        self.size
+    }
+
+    /// Returns the time taken to allocate a buffer
+    #[must_use]
+    pub fn allocation(&self) -> Percentiles {
+        self.allocation
+    }
+
+    /// Returns the time taken to map a buffer
+    #[must_use]
+    pub fn mmap(&self) -> Percentiles {
+        self.mmap
+    }
+
+    /// Returns the time taken to write the first byte of each page of a freshly mapped buffer
+    #[must_use]
+    pub fn first_touch(&self) -> Percentiles {
+        self.first_touch
+    }
+
+    /// Returns the time taken to fill a whole buffer, including the cache maintenance operations
+    #[must_use]
+    pub fn write(&self) -> Percentiles {
+        self.write
+    }
+
+    /// Returns the time taken to read a whole buffer, including the cache maintenance operations
+    #[must_use]
+    pub fn read(&self) -> Percentiles {
+        self.read
+    }
+
+    fn bandwidth(&self, duration: Duration) -> u64 {
+        let nanos = duration.as_nanos().max(1);
+        let bytes = u128::try_from(self.size).unwrap_or(u128::MAX);
+
+        u64::try_from(bytes.saturating_mul(1_000_000_000) / nanos).unwrap_or(u64::MAX)
+    }
+
+    /// Returns the median CPU write bandwidth, in bytes per second
+    #[must_use]
+    pub fn write_bandwidth(&self) -> u64 {
+        self.bandwidth(self.write.p50)
+    }
+
+    /// Returns the median CPU read bandwidth, in bytes per second
+    #[must_use]
+    pub fn read_bandwidth(&self) -> u64 {
+        self.bandwidth(self.read.p50)
+    }
+}
+
+/// Benchmark of the allocation and CPU access costs of DMA-Bufs
+///
+/// For each size, a buffer is allocated, mapped, touched, then written and read by the CPU, a
+/// given number of times. The buffers can come from any [`DmaBufAllocator`], or from a closure
+/// using [`Benchmark::run_with`].
+///
+/// # Example
+///
+/// ```no_run
+/// use dma_heap::{Benchmark, Heap, HeapKind};
+///
+/// let heap = Heap::new(HeapKind::System).unwrap();
+///
+/// for result in Benchmark::new().run(&heap).unwrap() {
+///     println!(
+///         "{} bytes: allocation {:?}, read {} B/s, write {} B/s",
+///         result.size(),
+///         result.allocation().p50(),
+///         result.read_bandwidth(),
+///         result.write_bandwidth()
+///     );
+/// }
+/// ```
+#[derive(Clone, Debug)]
+pub struct Benchmark {
+    sizes: Vec<usize>,
+    iterations: usize,
+}
+
+impl Default for Benchmark {
+    fn default() -> Self {
+        Self {
+            sizes: DEFAULT_SIZES.to_vec(),
+            iterations: DEFAULT_ITERATIONS,
+        }
+    }
+}
+
+impl Benchmark {
+    /// Creates a new benchmark, with sizes from 4 KiB to 256 MiB and 10 iterations per size
+    #[must_use]
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Sets the buffer sizes to benchmark, in bytes
+    #[must_use]
+    pub fn sizes(mut self, sizes: &[usize]) -> Self {
+        self.sizes = sizes.to_vec();
+        self
+    }
+
+    /// Sets the number of iterations to run for each size
+    #[must_use]
+    pub fn iterations(mut self, iterations: usize) -> Self {
+        self.iterations = iterations.max(1);
+        self
+    }
+
+    /// Runs the benchmark on a [`crate::Heap`], or any other [`DmaBufAllocator`]
+    ///
+    /// # Errors
+    ///
+    /// Will return [Error] if an allocation, mapping or CPU access fails.
+    pub fn run<A: DmaBufAllocator>(&self, allocator: &A) -> Result<Vec<BenchmarkResult>> {
+        self.run_with(|len| allocator.allocate(len))
+    }
+
+    /// Runs the benchmark on buffers returned by `allocate`
+    ///
+    /// The closure is called with the size to allocate, and must return a readable and writable
+    /// [`DmaBuf`].
+    ///
+    /// # Errors
+    ///
+    /// Will return [Error] if an allocation, mapping or CPU access fails.
+    pub fn run_with<F>(&self, mut allocate: F) -> Result<Vec<BenchmarkResult>>
+    where
+        F: FnMut(usize) -> Result<DmaBuf>,
+    {
+        self.sizes
+            .iter()
+            .map(|&size| self.run_size(&mut allocate, size))
+            .collect()
+    }
+
+    fn run_size<F>(&self, allocate: &mut F, size: usize) -> Result<BenchmarkResult>
+    where
+        F: FnMut(usize) -> Result<DmaBuf>,
+    {
+        debug!("Benchmarking {size} bytes buffers");
+
+        let page_size = page_size();
+        let mut allocation = Vec::with_capacity(self.iterations);
+        let mut mmap = Vec::with_capacity(self.iterations);
+        let mut first_touch = Vec::with_capacity(self.iterations);
+        let mut write = Vec::with_capacity(self.iterations);
+        let mut read = Vec::with_capacity(self.iterations);
+
+        for _ in 0..self.iterations {
+            let start = Instant::now();
+            let buffer = allocate(size)?;
+            allocation.push(start.elapsed());
+
+            let start = Instant::now();
+            let mut mapping = buffer.mmap()?;
+            mmap.push(start.elapsed());
+
+            let mut guard = mapping.write()?;
+            let start = Instant::now();
+            for page in guard.chunks_mut(page_size) {
+                if let Some(byte) = page.first_mut() {
+                    *byte = 1;
+                }
+            }
+            black_box(&mut *guard);
+            first_touch.push(start.elapsed());
+            drop(guard);
+
+            let start = Instant::now();
+            let mut guard = mapping.write()?;
+            guard.fill(0xa5);
+            black_box(&mut *guard);
+            drop(guard);
+            write.push(start.elapsed());
+
+            let start = Instant::now();
+            let guard = mapping.read()?;
+            let sum = guard
+                .iter()
+                .fold(0u64, |sum, byte| sum.wrapping_add(u64::from(*byte)));
+            black_box(sum);
+            drop(guard);
+            read.push(start.elapsed());
+        }
+
+        Ok(BenchmarkResult {
+            size,
+            allocation: Percentiles::from_samples(allocation),
+            mmap: Percentiles::from_samples(mmap),
+            first_touch: Percentiles::from_samples(first_touch),
+            write: Percentiles::from_samples(write),
+            read: Percentiles::from_samples(read),
+        })
+    }
+}
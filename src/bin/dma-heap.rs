@@ -0,0 +1,541 @@
+// Copyright 2020-2021, Cerno
+// Licensed under the MIT License
+// See the LICENSE file or <http://opensource.org/licenses/MIT>
+
+//! Command-line tool to inspect and test the DMA-Buf Heaps of a system
+
+use core::{error::Error, time::Duration};
+use std::{
+    env, fs,
+    io::{self, BufRead, Write},
+    os::{
+        fd::RawFd,
+        unix::{fs::FileTypeExt, net::UnixListener},
+    },
+    path::{Path, PathBuf},
+    process::ExitCode,
+    time::Instant,
+};
+
+use dma_heap::{
+    Benchmark, Broker, BrokerPolicy, ClientPolicy, DmaBufAttribution, DmaBufInfo, DmaBufStats,
+    Heap, HeapError, HeapKind, Udmabuf,
+};
+use log as _;
+use rustix as _;
+use strum_macros as _;
+use thiserror as _;
+#[cfg(any(test, feature = "tokio"))]
+use tokio as _;
+
+const USAGE: &str = "Usage: dma-heap <command> [arguments]
+
+Commands:
+    list                            List the DMA-Buf Heaps of the system
+    alloc <heap> <size|max> [hold]  Allocate a buffer, and optionally hold it until Enter is pressed
+    info <pid>:<fd>                 Show information about a DMA-Buf held by a process
+    stats [processes]               Show the DMA-Bufs allocated in the system
+    bench <heap|udmabuf> [size] [iterations] Measure the allocation and CPU access costs
+    broker <socket> <policy> [udmabuf] Serve allocations to unprivileged clients over a socket
+
+Heaps can be given by name (system, linux,cma, ...) or by path. Sizes accept the K, M and G
+suffixes.
+
+The broker policy file has one client per line, as default, uid:<uid> or cgroup:<path>,
+followed by its quota=<size>, buffers=<count> and heap=<name> settings. Lines starting with # are ignored.";
+
+/// Largest size we'll try to allocate when looking for the maximum allocation size
+const MAX_PROBE_SIZE: u64 = 1 << 40;
+
+#[derive(Debug)]
+enum CliError {
+    Usage(String),
+    Heap(HeapError),
+    Io(io::Error),
+}
+
+impl From<HeapError> for CliError {
+    fn from(err: HeapError) -> Self {
+        Self::Heap(err)
+    }
+}
+
+impl From<io::Error> for CliError {
+    fn from(err: io::Error) -> Self {
+        Self::Io(err)
+    }
+}
+
+type CliResult = Result<(), CliError>;
+
+fn parse_size(arg: &str) -> Result<usize, CliError> {
+    let arg = arg.trim_end_matches("iB").trim_end_matches('B');
+    let (digits, shift) = match arg.chars().last() {
+        Some('k' | 'K') => (&arg[..arg.len() - 1], 10),
+        Some('m' | 'M') => (&arg[..arg.len() - 1], 20),
+        Some('g' | 'G') => (&arg[..arg.len() - 1], 30),
+        _ => (arg, 0),
+    };
+
+    digits
+        .parse::<usize>()
+        .ok()
+        .and_then(|size| size.checked_mul(1 << shift))
+        .ok_or_else(|| CliError::Usage(format!("Invalid size: {arg}")))
+}
+
+fn parse_heap(arg: &str) -> Result<HeapKind, CliError> {
+    if arg.contains('/') {
+        return Ok(HeapKind::Custom(PathBuf::from(arg)));
+    }
+
+    let kind = Heap::list()?
+        .into_iter()
+        .find(|info| info.name() == arg)
+        .map_or_else(
+            || match arg {
+                "linux,cma" => HeapKind::Cma,
+                "system" => HeapKind::System,
+                _ => HeapKind::Custom(PathBuf::from("/dev/dma_heap").join(arg)),
+            },
+            |info| info.kind(),
+        );
+
+    Ok(kind)
+}
+
+/// Formats a duration in microseconds, with a nanosecond precision
+fn format_duration(duration: Duration) -> String {
+    format!(
+        "{}.{:03}us",
+        duration.as_micros(),
+        duration.subsec_nanos() % 1000
+    )
+}
+
+fn list(out: &mut impl Write) -> CliResult {
+    let heaps = Heap::list()?;
+
+    if heaps.is_empty() {
+        writeln!(out, "No DMA-Buf Heap found")?;
+        return Ok(());
+    }
+
+    for heap in heaps {
+        writeln!(
+            out,
+            "{}\t{}\t{}:{}",
+            heap.name(),
+            heap.path().display(),
+            heap.major(),
+            heap.minor()
+        )?;
+    }
+
+    Ok(())
+}
+
+/// Finds the largest allocation that succeeds on the Heap, with a page granularity
+fn probe_max(heap: &Heap) -> Result<usize, CliError> {
+    let page_size = rustix::param::page_size();
+    let max_size = usize::try_from(MAX_PROBE_SIZE).unwrap_or(usize::MAX);
+
+    let mut low = 0;
+    let mut high = page_size;
+
+    // First, find an upper bound by doubling the size until the allocation fails
+    loop {
+        match heap.allocate(high) {
+            Ok(_) => {
+                low = high;
+
+                // We're not going to try any larger allocation, so that's our answer
+                let Some(next) = high.checked_mul(2).filter(|next| *next <= max_size) else {
+                    return Ok(low);
+                };
+
+                high = next;
+            }
+            Err(HeapError::NoMemoryLeft { .. } | HeapError::InvalidAllocation { .. }) => break,
+            Err(err) => return Err(err.into()),
+        }
+    }
+
+    // And then bisect between the last success and the first failure
+    while high - low > page_size {
+        let mid = (low + (high - low) / 2).next_multiple_of(page_size);
+
+        match heap.allocate(mid) {
+            Ok(_) => low = mid,
+            Err(HeapError::NoMemoryLeft { .. } | HeapError::InvalidAllocation { .. }) => high = mid,
+            Err(err) => return Err(err.into()),
+        }
+    }
+
+    Ok(low)
+}
+
+fn alloc(out: &mut impl Write, args: &[String]) -> CliResult {
+    let [heap, size, rest @ ..] = args else {
+        return Err(CliError::Usage(String::from(
+            "alloc needs a heap and a size",
+        )));
+    };
+
+    let heap = Heap::new(parse_heap(heap)?)?;
+
+    if size == "max" {
+        let max = probe_max(&heap)?;
+        writeln!(out, "Largest allocation on {}: {max} bytes", heap.kind())?;
+        return Ok(());
+    }
+
+    let size = parse_size(size)?;
+    let start = Instant::now();
+    let buffer = heap.allocate(size)?;
+    let elapsed = start.elapsed();
+
+    writeln!(
+        out,
+        "Allocated {} bytes on {} in {}",
+        buffer.len(),
+        heap.kind(),
+        format_duration(elapsed)
+    )?;
+
+    if rest.first().is_some_and(|arg| arg == "hold") {
+        writeln!(out, "Holding the buffer, press Enter to release it")?;
+        out.flush()?;
+
+        let mut line = String::new();
+        io::stdin().lock().read_line(&mut line)?;
+    }
+
+    Ok(())
+}
+
+fn info(out: &mut impl Write, args: &[String]) -> CliResult {
+    let parsed = args.first().and_then(|arg| {
+        let (pid, fd) = arg.split_once(':')?;
+
+        Some((pid.parse::<u32>().ok()?, fd.parse::<RawFd>().ok()?))
+    });
+
+    let Some((pid, fd)) = parsed else {
+        return Err(CliError::Usage(String::from(
+            "info needs a <pid>:<fd> argument",
+        )));
+    };
+
+    let info = DmaBufInfo::from_pid_fd(pid, fd)?;
+
+    writeln!(out, "Size:     {} bytes", info.size())?;
+    writeln!(out, "Exporter: {}", info.exporter())?;
+    writeln!(out, "Name:     {}", info.name().unwrap_or("-"))?;
+    writeln!(out, "Inode:    {}", info.inode())?;
+    writeln!(out, "Count:    {}", info.count())?;
+
+    Ok(())
+}
+
+fn stats(out: &mut impl Write, args: &[String]) -> CliResult {
+    let stats = DmaBufStats::read()?;
+
+    for exporter in stats.exporters() {
+        writeln!(
+            out,
+            "{}\t{} buffers\t{} bytes",
+            exporter.name(),
+            exporter.count(),
+            exporter.size()
+        )?;
+    }
+
+    writeln!(
+        out,
+        "Total\t{} buffers\t{} bytes",
+        stats.buffers().len(),
+        stats.total_size()
+    )?;
+
+    if args.first().is_some_and(|arg| arg == "processes") {
+        writeln!(out)?;
+
+        for process in DmaBufAttribution::collect()?.processes() {
+            writeln!(
+                out,
+                "{}\t{}\t{} buffers\t{} bytes\t{} bytes proportional",
+                process.pid(),
+                process.comm(),
+                process.buffers().len(),
+                process.total_size(),
+                process.proportional_size()
+            )?;
+        }
+    }
+
+    Ok(())
+}
+
+fn bench(out: &mut impl Write, args: &[String]) -> CliResult {
+    let [heap, rest @ ..] = args else {
+        return Err(CliError::Usage(String::from("bench needs a heap")));
+    };
+
+    let mut benchmark = Benchmark::new();
+
+    if let Some(size) = rest.first() {
+        benchmark = benchmark.sizes(&[parse_size(size)?]);
+    }
+
+    if let Some(iterations) = rest.get(1) {
+        let iterations = iterations
+            .parse::<usize>()
+            .map_err(|_err| CliError::Usage(format!("Invalid iterations: {iterations}")))?;
+
+        benchmark = benchmark.iterations(iterations);
+    }
+
+    // udmabuf isn't a heap, but its buffers are worth comparing against the heaps' ones
+    let results = if heap == "udmabuf" {
+        let udmabuf = Udmabuf::new()?;
+        writeln!(out, "Benchmarking udmabuf")?;
+
+        benchmark.run(&udmabuf)?
+    } else {
+        let heap = Heap::new(parse_heap(heap)?)?;
+        writeln!(out, "Benchmarking the {} Heap", heap.kind())?;
+
+        benchmark.run(&heap)?
+    };
+
+    for result in results {
+        writeln!(
+            out,
+            "{} bytes\talloc {} (p99 {})\tmmap {}\tfirst touch {}\twrite {} MiB/s\tread {} MiB/s",
+            result.size(),
+            format_duration(result.allocation().p50()),
+            format_duration(result.allocation().p99()),
+            format_duration(result.mmap().p50()),
+            format_duration(result.first_touch().p50()),
+            result.write_bandwidth() >> 20,
+            result.read_bandwidth() >> 20,
+        )?;
+    }
+
+    Ok(())
+}
+
+fn parse_policy(content: &str) -> Result<BrokerPolicy, CliError> {
+    let mut policy = BrokerPolicy::new();
+
+    let lines = content
+        .lines()
+        .map(str::trim)
+        .filter(|line| !line.is_empty() && !line.starts_with('#'));
+
+    for line in lines {
+        let mut fields = line.split_whitespace();
+        let client = fields.next().unwrap_or_default();
+
+        let mut client_policy = ClientPolicy::new();
+        for field in fields {
+            client_policy = match field.split_once('=') {
+                Some(("quota", size)) => client_policy.quota(parse_size(size)?),
+                Some(("buffers", count)) => {
+                    let count = count.parse().map_err(|_err| {
+                        CliError::Usage(format!("Invalid buffer count: {count}"))
+                    })?;
+
+                    client_policy.max_buffers(count)
+                }
+                Some(("heap", name)) => client_policy.allow_heap(name),
+                _ => return Err(CliError::Usage(format!("Invalid policy setting: {field}"))),
+            };
+        }
+
+        policy = match client.split_once(':') {
+            None if client == "default" => policy.default_policy(client_policy),
+            Some(("uid", uid)) => {
+                let uid = uid
+                    .parse::<u32>()
+                    .map_err(|_err| CliError::Usage(format!("Invalid UID: {uid}")))?;
+
+                policy.uid(uid, client_policy)
+            }
+            Some(("cgroup", path)) => policy.cgroup(path, client_policy),
+            _ => return Err(CliError::Usage(format!("Invalid policy client: {client}"))),
+        };
+    }
+
+    Ok(policy)
+}
+
+fn broker(out: &mut impl Write, args: &[String]) -> CliResult {
+    let [socket, policy, rest @ ..] = args else {
+        return Err(CliError::Usage(String::from(
+            "broker needs a socket and a policy file",
+        )));
+    };
+
+    let policy = parse_policy(&fs::read_to_string(policy)?)?;
+    let mut broker = Broker::new(policy).with_system_heaps()?;
+
+    if rest.first().is_some_and(|arg| arg == "udmabuf") {
+        broker = broker.heap("udmabuf", Udmabuf::new()?);
+    }
+
+    // A socket left over by a previous instance would make bind() fail, but we don't want to
+    // remove anything else that would be in the way.
+    let socket = Path::new(socket);
+    match fs::symlink_metadata(socket) {
+        Ok(metadata) if metadata.file_type().is_socket() => fs::remove_file(socket)?,
+        Ok(_) => {
+            return Err(io::Error::new(
+                io::ErrorKind::AlreadyExists,
+                format!("{} already exists and isn't a socket", socket.display()),
+            )
+            .into())
+        }
+        Err(err) if err.kind() == io::ErrorKind::NotFound => {}
+        Err(err) => return Err(err.into()),
+    }
+
+    let listener = UnixListener::bind(socket)?;
+
+    writeln!(out, "Listening on {}", socket.display())?;
+    out.flush()?;
+
+    broker.serve(&listener)?;
+
+    Ok(())
+}
+
+fn run(args: &[String]) -> CliResult {
+    let mut out = io::stdout().lock();
+
+    match args {
+        [command, args @ ..] => match command.as_str() {
+            "list" => list(&mut out),
+            "alloc" => alloc(&mut out, args),
+            "info" => info(&mut out, args),
+            "stats" => stats(&mut out, args),
+            "bench" => bench(&mut out, args),
+            "broker" => broker(&mut out, args),
+            _ => Err(CliError::Usage(format!("Unknown command: {command}"))),
+        },
+        [] => Err(CliError::Usage(String::from("Missing command"))),
+    }
+}
+
+fn main() -> ExitCode {
+    let args = env::args().skip(1).collect::<Vec<_>>();
+
+    match run(&args) {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(CliError::Usage(msg)) => {
+            eprintln!("{msg}\n\n{USAGE}");
+            ExitCode::from(2)
+        }
+        Err(CliError::Heap(err)) => {
+            eprintln!("Error: {err}");
+
+            let mut source = err.source();
+            while let Some(err) = source {
+                eprintln!("Caused by: {err}");
+                source = err.source();
+            }
+
+            if let HeapError::NoMemoryLeft { cma: Some(cma), .. } = &err {
+                eprintln!(
+                    "CMA: {} bytes free out of {} bytes",
+                    cma.free().unwrap_or_default(),
+                    cma.total().unwrap_or_default()
+                );
+            }
+
+            ExitCode::FAILURE
+        }
+        Err(CliError::Io(err)) => {
+            eprintln!("Error: {err}");
+            ExitCode::FAILURE
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::PathBuf;
+
+    use dma_heap::{BrokerPolicy, ClientPolicy};
+
+    use super::{parse_policy, parse_size, CliError};
+
+    #[test]
+    fn sizes() {
+        assert_eq!(parse_size("4096").unwrap(), 4096);
+        assert_eq!(parse_size("4K").unwrap(), 4 << 10);
+        assert_eq!(parse_size("16M").unwrap(), 16 << 20);
+        assert_eq!(parse_size("16MiB").unwrap(), 16 << 20);
+        assert_eq!(parse_size("2g").unwrap(), 2 << 30);
+        assert_eq!(parse_size("512KB").unwrap(), 512 << 10);
+    }
+
+    #[test]
+    fn invalid_sizes() {
+        for size in ["", "M", "12X", "-1", "1.5M", "99999999999999999999G"] {
+            assert!(
+                matches!(parse_size(size), Err(CliError::Usage(_))),
+                "{size}"
+            );
+        }
+    }
+
+    #[test]
+    fn policy() {
+        let policy = parse_policy(
+            "# Camera pipeline
+cgroup:/system.slice/camera.service quota=512M heap=system heap=linux,cma
+
+uid:1000 quota=64M buffers=16 heap=system
+default quota=16M heap=system
+",
+        )
+        .unwrap();
+
+        let expected = BrokerPolicy::new()
+            .cgroup(
+                PathBuf::from("/system.slice/camera.service"),
+                ClientPolicy::new()
+                    .quota(512 << 20)
+                    .allow_heap("system")
+                    .allow_heap("linux,cma"),
+            )
+            .uid(
+                1000,
+                ClientPolicy::new()
+                    .quota(64 << 20)
+                    .max_buffers(16)
+                    .allow_heap("system"),
+            )
+            .default_policy(ClientPolicy::new().quota(16 << 20).allow_heap("system"));
+
+        assert_eq!(policy, expected);
+    }
+
+    #[test]
+    fn invalid_policies() {
+        for policy in [
+            "everyone quota=1M",
+            "uid:root quota=1M",
+            "default quota=lots",
+            "default buffers=-1",
+            "default heaps=system",
+        ] {
+            assert!(
+                matches!(parse_policy(policy), Err(CliError::Usage(_))),
+                "{policy}"
+            );
+        }
+    }
+}
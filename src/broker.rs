@@ -0,0 +1,899 @@
+use alloc::collections::{BTreeMap, BTreeSet};
+use core::{
+    fmt,
+    sync::atomic::{AtomicU64, AtomicUsize, Ordering},
+    time::Duration,
+};
+use std::{
+    fs,
+    io::{self, BufRead, BufReader, Read, Write},
+    os::{
+        fd::AsFd,
+        unix::net::{UnixListener, UnixStream},
+    },
+    path::{Path, PathBuf},
+    sync::{Mutex, MutexGuard, PoisonError},
+    thread,
+};
+
+use log::{debug, info, warn};
+use rustix::{fs::fstat, io::Errno, net::sockopt::get_socket_peercred, param::page_size};
+
+use crate::{
+    info::is_dma_buf, recv_buffers, send_buffers, BufferStats, DmaBuf, DmaBufAllocator,
+    DmaBufStats, Heap, HeapError, Result,
+};
+
+/// Maximum length of a request line, newline included
+const MAX_LINE_LEN: usize = 256;
+
+/// Default maximum number of clients served at the same time
+const DEFAULT_MAX_CONNECTIONS: usize = 64;
+
+/// Default maximum number of connections of a single UID served at the same time
+const DEFAULT_MAX_CONNECTIONS_PER_UID: usize = 8;
+
+/// Default time after which an idle connection is closed
+const DEFAULT_READ_TIMEOUT: Duration = Duration::from_mins(1);
+
+/// Default maximum number of buffers a client can hold at any given time
+const DEFAULT_MAX_BUFFERS: usize = 256;
+
+/// Delay before accepting connections again after a failure, ie. when running out of file
+/// descriptors
+const ACCEPT_RETRY_DELAY: Duration = Duration::from_millis(100);
+
+/// Allocation policy applied to the clients of a [`Broker`]
+///
+/// By default, a client isn't allowed to allocate anything.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ClientPolicy {
+    quota: usize,
+    max_buffers: usize,
+    heaps: Vec<String>,
+}
+
+impl Default for ClientPolicy {
+    fn default() -> Self {
+        Self {
+            quota: 0,
+            max_buffers: DEFAULT_MAX_BUFFERS,
+            heaps: Vec::new(),
+        }
+    }
+}
+
+impl ClientPolicy {
+    /// Creates a new policy, without any Heap allowed
+    #[must_use]
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Sets the maximum number of bytes the client can hold at any given time
+    #[must_use]
+    pub fn quota(mut self, bytes: usize) -> Self {
+        self.quota = bytes;
+        self
+    }
+
+    /// Sets the maximum number of buffers the client can hold at any given time
+    ///
+    /// Defaults to 256.
+    #[must_use]
+    pub fn max_buffers(mut self, count: usize) -> Self {
+        self.max_buffers = count;
+        self
+    }
+
+    /// Allows the client to allocate from the Heap with the given name
+    #[must_use]
+    pub fn allow_heap(mut self, name: &str) -> Self {
+        self.heaps.push(name.to_owned());
+        self
+    }
+
+    fn allows(&self, heap: &str) -> bool {
+        self.heaps.iter().any(|allowed| allowed == heap)
+    }
+}
+
+/// The identity the quotas are accounted against
+#[derive(Clone, Debug, PartialEq, Eq, PartialOrd, Ord)]
+enum ClientKey {
+    Uid(u32),
+    Cgroup(PathBuf),
+}
+
+impl fmt::Display for ClientKey {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Uid(uid) => write!(f, "uid {uid}"),
+            Self::Cgroup(path) => write!(f, "cgroup {}", path.display()),
+        }
+    }
+}
+
+/// The policies applied by a [`Broker`] to its clients
+///
+/// A client is matched, in order, against the cgroups, its UID, and the default policy. Clients
+/// matching a cgroup share the quota of that cgroup, while the other clients have a quota per
+/// UID. Clients that don't match any policy are rejected.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct BrokerPolicy {
+    default: Option<ClientPolicy>,
+    uids: BTreeMap<u32, ClientPolicy>,
+    cgroups: BTreeMap<PathBuf, ClientPolicy>,
+}
+
+impl BrokerPolicy {
+    /// Creates a new policy, rejecting all the clients
+    #[must_use]
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Sets the policy of the clients that don't match any cgroup or UID
+    #[must_use]
+    pub fn default_policy(mut self, policy: ClientPolicy) -> Self {
+        self.default = Some(policy);
+        self
+    }
+
+    /// Sets the policy of the clients running with the given UID
+    #[must_use]
+    pub fn uid(mut self, uid: u32, policy: ClientPolicy) -> Self {
+        self.uids.insert(uid, policy);
+        self
+    }
+
+    /// Sets the policy of the clients in the given cgroup, or in any of its descendants
+    ///
+    /// The path is relative to the root of the cgroup v2 hierarchy, ie.
+    /// `/system.slice/camera.service`.
+    #[must_use]
+    pub fn cgroup<P: Into<PathBuf>>(mut self, path: P, policy: ClientPolicy) -> Self {
+        self.cgroups.insert(path.into(), policy);
+        self
+    }
+
+    fn resolve(&self, uid: u32, cgroup: Option<&Path>) -> Option<(ClientKey, &ClientPolicy)> {
+        // The longest matching cgroup is the most specific one
+        let by_cgroup = cgroup.and_then(|cgroup| {
+            self.cgroups
+                .iter()
+                .filter(|(path, _)| cgroup.starts_with(path))
+                .max_by_key(|(path, _)| path.components().count())
+        });
+
+        if let Some((path, policy)) = by_cgroup {
+            return Some((ClientKey::Cgroup(path.clone()), policy));
+        }
+
+        self.uids
+            .get(&uid)
+            .or(self.default.as_ref())
+            .map(|policy| (ClientKey::Uid(uid), policy))
+    }
+}
+
+/// Reads the cgroup v2 path of a process
+fn process_cgroup(pid: i32) -> Option<PathBuf> {
+    let content = fs::read_to_string(format!("/proc/{pid}/cgroup")).ok()?;
+
+    content
+        .lines()
+        .find_map(|line| line.strip_prefix("0::"))
+        .map(PathBuf::from)
+}
+
+#[derive(Debug)]
+struct Allocation {
+    client: ClientKey,
+    connection: u64,
+    size: usize,
+    serial: u64,
+
+    // The inode of the buffer, to find out when the clients are done with it, if it's a DMA-Buf
+    inode: Option<u64>,
+}
+
+/// The DMA-Bufs allocated in the system at some point in time
+#[derive(Debug)]
+struct LiveBuffers {
+    // The allocations made after the snapshot aren't part of it
+    serial: u64,
+    inodes: BTreeSet<u64>,
+}
+
+#[derive(Debug, Default)]
+struct BrokerState {
+    allocations: Vec<Allocation>,
+    connections: BTreeSet<u64>,
+    uid_connections: BTreeMap<u32, usize>,
+    next_serial: u64,
+}
+
+impl BrokerState {
+    /// Drops the allocations that have been freed by the clients
+    ///
+    /// Without the list of the DMA-Bufs of the system, we can't tell whether a buffer is still
+    /// around, so we consider it freed when its connection is closed. The same goes for the
+    /// buffers that aren't DMA-Bufs, ie. from the mock backend.
+    fn prune(&mut self, live: Option<&LiveBuffers>) {
+        let connections = &self.connections;
+
+        self.allocations
+            .retain(|allocation| match (allocation.inode, live) {
+                (Some(inode), Some(live)) if allocation.serial < live.serial => {
+                    live.inodes.contains(&inode)
+                }
+                (Some(_), Some(_)) => true,
+                _ => connections.contains(&allocation.connection),
+            });
+    }
+
+    fn usage(&self, client: &ClientKey) -> (usize, usize) {
+        self.allocations
+            .iter()
+            .filter(|allocation| allocation.client == *client)
+            .fold((0, 0), |(count, size), allocation| {
+                (count + 1, size + allocation.size)
+            })
+    }
+}
+
+/// An allocation service handing out DMA-Bufs to unprivileged clients over a Unix socket
+///
+/// The broker opens the Heaps, or any other [`DmaBufAllocator`], and clients connect to it using
+/// a [`BrokerClient`] to allocate buffers, subject to a [`BrokerPolicy`] that restricts the
+/// Heaps they can use and how much memory they can hold. Clients are identified through the
+/// credentials of their socket.
+///
+/// A buffer is accounted against its client's quota until all the references to it are dropped,
+/// which requires the broker to be able to read the [`DmaBufStats`]. Otherwise, and for the
+/// buffers that aren't DMA-Bufs, like the ones of a [`crate::MockHeap`], buffers are accounted
+/// until the connection they were allocated on is closed.
+///
+/// # Example
+///
+/// ```no_run
+/// use std::os::unix::net::UnixListener;
+///
+/// use dma_heap::{Broker, BrokerPolicy, ClientPolicy};
+///
+/// let policy = BrokerPolicy::new()
+///     .default_policy(ClientPolicy::new().quota(64 << 20).allow_heap("system"));
+///
+/// let broker = Broker::new(policy).with_system_heaps().unwrap();
+/// let listener = UnixListener::bind("/run/dma-heap.sock").unwrap();
+///
+/// broker.serve(&listener).unwrap();
+/// ```
+pub struct Broker {
+    heaps: BTreeMap<String, Box<dyn DmaBufAllocator + Send + Sync>>,
+    policy: BrokerPolicy,
+    state: Mutex<BrokerState>,
+    next_connection: AtomicU64,
+    max_connections: usize,
+    max_connections_per_uid: usize,
+    read_timeout: Duration,
+}
+
+impl fmt::Debug for Broker {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Broker")
+            .field("heaps", &self.heaps.keys().collect::<Vec<_>>())
+            .field("policy", &self.policy)
+            .field("state", &self.state)
+            .field("max_connections", &self.max_connections)
+            .field("max_connections_per_uid", &self.max_connections_per_uid)
+            .field("read_timeout", &self.read_timeout)
+            .finish_non_exhaustive()
+    }
+}
+
+impl Broker {
+    /// Creates a new broker applying the given policy, without any Heap
+    #[must_use]
+    pub fn new(policy: BrokerPolicy) -> Self {
+        Self {
+            heaps: BTreeMap::new(),
+            policy,
+            state: Mutex::new(BrokerState::default()),
+            next_connection: AtomicU64::new(0),
+            max_connections: DEFAULT_MAX_CONNECTIONS,
+            max_connections_per_uid: DEFAULT_MAX_CONNECTIONS_PER_UID,
+            read_timeout: DEFAULT_READ_TIMEOUT,
+        }
+    }
+
+    /// Sets the maximum number of clients served at the same time by [`Broker::serve`]
+    ///
+    /// The connections accepted past that limit are closed right away. Defaults to 64.
+    #[must_use]
+    pub fn max_connections(mut self, max: usize) -> Self {
+        self.max_connections = max;
+        self
+    }
+
+    /// Sets the maximum number of connections of a single UID served at the same time
+    ///
+    /// The connections past that limit are rejected. Defaults to 8.
+    #[must_use]
+    pub fn max_connections_per_uid(mut self, max: usize) -> Self {
+        self.max_connections_per_uid = max;
+        self
+    }
+
+    /// Sets the time after which a client that doesn't send any request is disconnected
+    ///
+    /// Defaults to 60 seconds.
+    #[must_use]
+    pub fn read_timeout(mut self, timeout: Duration) -> Self {
+        self.read_timeout = timeout;
+        self
+    }
+
+    /// Makes an allocator available to the clients, under the given name
+    #[must_use]
+    pub fn heap<A>(mut self, name: &str, allocator: A) -> Self
+    where
+        A: DmaBufAllocator + Send + Sync + 'static,
+    {
+        self.heaps.insert(name.to_owned(), Box::new(allocator));
+        self
+    }
+
+    /// Makes all the DMA-Buf Heaps of the system available to the clients, under their name
+    ///
+    /// The Heaps that are missing, that we aren't allowed to open, or that don't look like DMA-Buf
+    /// Heaps are skipped.
+    ///
+    /// # Errors
+    ///
+    /// Will return [Error] if the Heaps can't be listed, or if opening one fails for any other
+    /// reason.
+    pub fn with_system_heaps(mut self) -> Result<Self> {
+        for info in Heap::list()? {
+            let heap = match Heap::new(info.kind()) {
+                Ok(heap) => heap,
+                Err(
+                    err @ (HeapError::Missing { .. }
+                    | HeapError::NotADmaHeap { .. }
+                    | HeapError::PermissionDenied { .. }),
+                ) => {
+                    warn!("Skipping the {} Heap: {err}", info.name());
+                    continue;
+                }
+                Err(err) => return Err(err),
+            };
+
+            self = self.heap(info.name(), heap);
+        }
+
+        Ok(self)
+    }
+
+    fn state(&self) -> MutexGuard<'_, BrokerState> {
+        self.state.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    /// Accepts connections on a socket and serves them, each in its own thread
+    ///
+    /// At most [`Broker::max_connections`] clients are served at the same time, the other
+    /// connections being closed as soon as they are accepted. It only returns once the listener
+    /// is shut down.
+    ///
+    /// # Errors
+    ///
+    /// Will return [Error] if the listener isn't usable anymore.
+    pub fn serve(&self, listener: &UnixListener) -> Result<()> {
+        let active = AtomicUsize::new(0);
+
+        thread::scope(|scope| {
+            for stream in listener.incoming() {
+                let stream = match stream {
+                    Ok(stream) => stream,
+                    Err(err) if is_accept_error_fatal(&err) => return Err(err.into()),
+                    Err(err) => {
+                        warn!("Couldn't accept a connection: {err}");
+
+                        // Running out of file descriptors or memory would make us spin otherwise
+                        thread::sleep(ACCEPT_RETRY_DELAY);
+                        continue;
+                    }
+                };
+
+                if active.fetch_add(1, Ordering::AcqRel) >= self.max_connections {
+                    active.fetch_sub(1, Ordering::AcqRel);
+                    warn!("Too many clients connected, closing the new connection");
+                    continue;
+                }
+
+                let active = &active;
+                scope.spawn(move || {
+                    if let Err(err) = self.handle(&stream) {
+                        warn!("Client connection failed: {err}");
+                    }
+
+                    active.fetch_sub(1, Ordering::AcqRel);
+                });
+            }
+
+            Ok(())
+        })
+    }
+
+    /// Serves the requests of a single client, until it closes the connection
+    ///
+    /// # Errors
+    ///
+    /// Will return [Error] if the client credentials can't be retrieved, if the client doesn't
+    /// match any policy or has too many connections already, or if the connection fails.
+    pub fn handle(&self, stream: &UnixStream) -> Result<()> {
+        let creds = get_socket_peercred(stream).map_err(io::Error::from)?;
+        let pid = creds.pid.as_raw_nonzero().get();
+        let uid = creds.uid.as_raw();
+        let cgroup = process_cgroup(pid);
+
+        let Some(client) = self.policy.resolve(uid, cgroup.as_deref()) else {
+            info!("Rejecting client, pid {pid}, uid {uid}, without any policy");
+            return Err(io::Error::from_raw_os_error(Errno::ACCESS.raw_os_error()).into());
+        };
+
+        let connection = self.next_connection.fetch_add(1, Ordering::Relaxed);
+
+        {
+            let mut state = self.state();
+            let uid_connections = state.uid_connections.entry(uid).or_default();
+            if *uid_connections >= self.max_connections_per_uid {
+                warn!("Too many connections for uid {uid}, rejecting client, pid {pid}");
+                return Err(io::Error::from_raw_os_error(Errno::USERS.raw_os_error()).into());
+            }
+
+            *uid_connections += 1;
+            state.connections.insert(connection)
+        };
+
+        info!("Client connected, pid {pid}, accounted as {}", client.0);
+
+        let res = stream
+            .set_read_timeout(Some(self.read_timeout))
+            .map_err(HeapError::from)
+            .and_then(|()| self.handle_requests(stream, &client, connection));
+
+        let live = self.live_buffers();
+        let mut state = self.state();
+        state.connections.remove(&connection);
+        if let Some(count) = state.uid_connections.get_mut(&uid) {
+            *count -= 1;
+            if *count == 0 {
+                state.uid_connections.remove(&uid);
+            }
+        }
+        state.prune(live.as_ref());
+
+        res
+    }
+
+    /// Retrieves the DMA-Bufs allocated in the system, if any allocation needs it
+    ///
+    /// This is done without holding the lock, since it has to go through every DMA-Buf.
+    fn live_buffers(&self) -> Option<LiveBuffers> {
+        let serial = {
+            let state = self.state();
+            if !state
+                .allocations
+                .iter()
+                .any(|allocation| allocation.inode.is_some())
+            {
+                return None;
+            }
+
+            state.next_serial
+        };
+
+        let stats = DmaBufStats::read()
+            .inspect_err(|err| debug!("Couldn't retrieve the DMA-Bufs of the system: {err}"))
+            .ok()?;
+
+        Some(LiveBuffers {
+            serial,
+            inodes: stats.buffers().iter().map(BufferStats::inode).collect(),
+        })
+    }
+
+    fn handle_requests(
+        &self,
+        stream: &UnixStream,
+        client: &(ClientKey, &ClientPolicy),
+        connection: u64,
+    ) -> Result<()> {
+        let mut reader = BufReader::new(stream);
+
+        loop {
+            let mut line = String::new();
+            let len = (&mut reader)
+                .take(MAX_LINE_LEN as u64)
+                .read_line(&mut line)?;
+
+            if len == 0 {
+                return Ok(());
+            }
+
+            // The rest of the line would be parsed as another request, so we give up on the
+            // client altogether.
+            if len == MAX_LINE_LEN && !line.ends_with('\n') {
+                warn!("Request too long, closing the connection");
+
+                let errno = Errno::MSGSIZE.raw_os_error();
+                send_buffers(stream, &[], format!("err {errno}").as_bytes())?;
+
+                return Err(io::Error::from_raw_os_error(errno).into());
+            }
+
+            let res = match line.split_whitespace().collect::<Vec<_>>().as_slice() {
+                ["alloc", heap, len] => match len.parse() {
+                    Ok(len) => self.allocate(client, connection, heap, len),
+                    Err(_) => Err(io::Error::from(io::ErrorKind::InvalidInput).into()),
+                },
+                _ => Err(io::Error::from(io::ErrorKind::InvalidInput).into()),
+            };
+
+            // The response is a buffer message, with the status as its metadata
+            match res {
+                Ok(buffer) => send_buffers(stream, &[&buffer], b"ok")?,
+                Err(err) => {
+                    debug!("Request '{}' failed: {err}", line.trim());
+
+                    let errno = err.errno().unwrap_or(Errno::IO.raw_os_error());
+                    send_buffers(stream, &[], format!("err {errno}").as_bytes())?;
+                }
+            }
+        }
+    }
+
+    fn allocate(
+        &self,
+        (client, policy): &(ClientKey, &ClientPolicy),
+        connection: u64,
+        heap: &str,
+        len: usize,
+    ) -> Result<DmaBuf> {
+        if !policy.allows(heap) {
+            warn!("{client} isn't allowed to allocate from {heap}");
+            return Err(io::Error::from_raw_os_error(Errno::ACCESS.raw_os_error()).into());
+        }
+
+        let Some(allocator) = self.heaps.get(heap) else {
+            return Err(io::Error::from_raw_os_error(Errno::NOENT.raw_os_error()).into());
+        };
+
+        // The buffers are accounted with their actual size, rounded up to the page size
+        let Some(size) = len.checked_next_multiple_of(page_size()) else {
+            return Err(io::Error::from_raw_os_error(Errno::DQUOT.raw_os_error()).into());
+        };
+
+        let live = self.live_buffers();
+
+        // We keep the lock during the allocation so that concurrent allocations can't overcommit
+        let mut state = self.state();
+        state.prune(live.as_ref());
+
+        let (count, usage) = state.usage(client);
+        if count >= policy.max_buffers {
+            warn!(
+                "{client} holds too many buffers: {count} buffers, {} allowed",
+                policy.max_buffers
+            );
+            return Err(io::Error::from_raw_os_error(Errno::DQUOT.raw_os_error()).into());
+        }
+
+        if usage
+            .checked_add(size)
+            .is_none_or(|total| total > policy.quota)
+        {
+            warn!(
+                "{client} is over its quota: {usage} bytes used, {size} bytes requested, {} \
+                 bytes allowed",
+                policy.quota
+            );
+            return Err(io::Error::from_raw_os_error(Errno::DQUOT.raw_os_error()).into());
+        }
+
+        let buffer = allocator.allocate(len)?;
+
+        // Only the DMA-Bufs show up in the stats, the other buffers are tracked by connection
+        let inode = match is_dma_buf(buffer.as_fd()) {
+            Ok(true) => fstat(&buffer).ok().map(|stat| stat.st_ino),
+            _ => None,
+        };
+
+        let serial = state.next_serial;
+        state.next_serial += 1;
+        state.allocations.push(Allocation {
+            client: client.clone(),
+            connection,
+            size: buffer.len(),
+            serial,
+            inode,
+        });
+
+        debug!("Allocated {} bytes from {heap} for {client}", buffer.len());
+
+        Ok(buffer)
+    }
+}
+
+/// Returns whether an error returned by `accept()` means that the listener isn't usable anymore
+fn is_accept_error_fatal(err: &io::Error) -> bool {
+    [Errno::BADF, Errno::INVAL, Errno::NOTSOCK, Errno::OPNOTSUPP]
+        .iter()
+        .any(|errno| err.raw_os_error() == Some(errno.raw_os_error()))
+}
+
+/// A client of a [`Broker`]
+///
+/// # Example
+///
+/// ```no_run
+/// use dma_heap::BrokerClient;
+///
+/// let mut client = BrokerClient::connect("/run/dma-heap.sock").unwrap();
+/// let buffer = client.allocate("system", 4096).unwrap();
+/// ```
+#[derive(Debug)]
+pub struct BrokerClient {
+    stream: UnixStream,
+}
+
+impl BrokerClient {
+    /// Connects to a [`Broker`] listening on the given socket
+    ///
+    /// # Errors
+    ///
+    /// Will return [Error] if the connection fails.
+    pub fn connect<P: AsRef<Path>>(path: P) -> Result<Self> {
+        Ok(Self::from_stream(UnixStream::connect(path)?))
+    }
+
+    /// Creates a client out of a connected socket
+    #[must_use]
+    pub fn from_stream(stream: UnixStream) -> Self {
+        Self { stream }
+    }
+
+    /// Allocates a buffer from the Heap with the given name
+    ///
+    /// # Errors
+    ///
+    /// Will return [Error] if the connection fails or if the broker rejected the allocation,
+    /// with the errno of the failure: `EACCES` if the Heap isn't allowed, `ENOENT` if it doesn't
+    /// exist, `EDQUOT` if the quota has been exceeded, or the error of the Heap.
+    pub fn allocate(&mut self, heap: &str, len: usize) -> Result<DmaBuf> {
+        if heap.is_empty() || heap.contains(char::is_whitespace) {
+            return Err(io::Error::from(io::ErrorKind::InvalidInput).into());
+        }
+
+        self.stream
+            .write_all(format!("alloc {heap} {len}\n").as_bytes())?;
+
+        let (mut buffers, status) = recv_buffers(&self.stream)?.into_parts();
+        let status = String::from_utf8_lossy(&status);
+        let invalid = || HeapError::from(io::Error::from(io::ErrorKind::InvalidData));
+
+        match status.split_whitespace().collect::<Vec<_>>().as_slice() {
+            ["ok"] => {
+                let buffer = buffers.pop().ok_or_else(invalid)?;
+                if !buffers.is_empty() {
+                    return Err(invalid());
+                }
+
+                Ok(buffer)
+            }
+            ["err", errno] => {
+                let errno = errno.parse().map_err(|_err| invalid())?;
+
+                Err(io::Error::from_raw_os_error(errno).into())
+            }
+            _ => Err(invalid()),
+        }
+    }
+}
+
+#[cfg(test)]
+#[cfg(feature = "mock")]
+mod tests {
+    use core::time::Duration;
+    use std::{
+        env, fs,
+        io::Write,
+        os::unix::net::{UnixListener, UnixStream},
+        path::PathBuf,
+        process, thread,
+    };
+
+    use rustix::{io::Errno, param::page_size};
+
+    use super::MAX_LINE_LEN;
+    use crate::{
+        recv_buffers, transfer::accept_mock_buffers, Broker, BrokerClient, BrokerPolicy,
+        ClientPolicy, Heap, HeapKind, MockHeap,
+    };
+
+    const QUOTA: usize = 64 << 10;
+
+    fn broker() -> Broker {
+        broker_with(ClientPolicy::new().quota(QUOTA).allow_heap("system"))
+    }
+
+    fn broker_with(policy: ClientPolicy) -> Broker {
+        Broker::new(BrokerPolicy::new().default_policy(policy))
+            .heap("system", Heap::mock(MockHeap::new(HeapKind::System)))
+            .heap("linux,cma", Heap::mock(MockHeap::new(HeapKind::Cma)))
+    }
+
+    fn listen(name: &str) -> (PathBuf, UnixListener) {
+        let path = env::temp_dir().join(format!("dma-heap-{name}-{}.sock", process::id()));
+        if path.exists() {
+            fs::remove_file(&path).unwrap();
+        }
+
+        let listener = UnixListener::bind(&path).unwrap();
+
+        (path, listener)
+    }
+
+    /// Serves a single connection, until the client passed to `test` is dropped
+    fn connect<F>(broker: &Broker, name: &str, test: F)
+    where
+        F: FnOnce(BrokerClient),
+    {
+        let (path, listener) = listen(name);
+
+        thread::scope(|scope| {
+            let server = scope.spawn(|| {
+                let (stream, _) = listener.accept().unwrap();
+
+                broker.handle(&stream)
+            });
+
+            accept_mock_buffers();
+            test(BrokerClient::connect(&path).unwrap());
+
+            server.join().unwrap().unwrap();
+        });
+
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn quota() {
+        let broker = broker();
+
+        connect(&broker, "quota", |mut client| {
+            let _first = client.allocate("system", QUOTA / 2).unwrap();
+            let _second = client.allocate("system", QUOTA / 2).unwrap();
+
+            let err = client.allocate("system", 4096).unwrap_err();
+            assert_eq!(err.errno(), Some(Errno::DQUOT.raw_os_error()));
+        });
+    }
+
+    #[test]
+    fn quota_counts_pages() {
+        let broker = broker();
+
+        connect(&broker, "quota-pages", |mut client| {
+            let pages = QUOTA / page_size();
+            let _buffers = (0..pages)
+                .map(|_| client.allocate("system", 1).unwrap())
+                .collect::<Vec<_>>();
+
+            let err = client.allocate("system", 1).unwrap_err();
+            assert_eq!(err.errno(), Some(Errno::DQUOT.raw_os_error()));
+        });
+    }
+
+    #[test]
+    fn max_buffers() {
+        let broker = broker_with(
+            ClientPolicy::new()
+                .quota(QUOTA)
+                .max_buffers(2)
+                .allow_heap("system"),
+        );
+
+        connect(&broker, "max-buffers", |mut client| {
+            let _first = client.allocate("system", 4096).unwrap();
+            let _second = client.allocate("system", 4096).unwrap();
+
+            let err = client.allocate("system", 4096).unwrap_err();
+            assert_eq!(err.errno(), Some(Errno::DQUOT.raw_os_error()));
+        });
+    }
+
+    #[test]
+    fn reject_unknown_clients() {
+        let broker = Broker::new(BrokerPolicy::new());
+        let (_client, server) = UnixStream::pair().unwrap();
+
+        let err = broker.handle(&server).unwrap_err();
+        assert_eq!(err.errno(), Some(Errno::ACCESS.raw_os_error()));
+    }
+
+    #[test]
+    fn max_connections_per_uid() {
+        let broker = broker().max_connections_per_uid(1);
+
+        connect(&broker, "per-uid", |mut client| {
+            // Makes sure that the first connection is being served
+            let _buffer = client.allocate("system", 4096).unwrap();
+
+            let (_client, server) = UnixStream::pair().unwrap();
+            let err = broker.handle(&server).unwrap_err();
+            assert_eq!(err.errno(), Some(Errno::USERS.raw_os_error()));
+        });
+    }
+
+    #[test]
+    fn read_timeout() {
+        let broker = broker().read_timeout(Duration::from_millis(10));
+        let (_client, server) = UnixStream::pair().unwrap();
+
+        broker.handle(&server).unwrap_err();
+    }
+
+    #[test]
+    fn heap_allow_list() {
+        let broker = broker();
+
+        connect(&broker, "allow-list", |mut client| {
+            let err = client.allocate("linux,cma", 4096).unwrap_err();
+            assert_eq!(err.errno(), Some(Errno::ACCESS.raw_os_error()));
+        });
+    }
+
+    #[test]
+    fn release_on_disconnect() {
+        let broker = broker();
+
+        connect(&broker, "release-first", |mut client| {
+            let _buffer = client.allocate("system", QUOTA).unwrap();
+        });
+
+        connect(&broker, "release-second", |mut client| {
+            let _buffer = client.allocate("system", QUOTA).unwrap();
+        });
+    }
+
+    #[test]
+    fn overlong_request() {
+        let broker = broker();
+        let (path, listener) = listen("overlong");
+
+        thread::scope(|scope| {
+            let server = scope.spawn(|| {
+                let (stream, _) = listener.accept().unwrap();
+
+                broker.handle(&stream)
+            });
+
+            let mut stream = UnixStream::connect(&path).unwrap();
+            let request = format!("alloc system {}\n", "0".repeat(MAX_LINE_LEN));
+            stream.write_all(request.as_bytes()).unwrap();
+
+            let message = recv_buffers(&stream).unwrap();
+            let expected = format!("err {}", Errno::MSGSIZE.raw_os_error());
+            assert_eq!(message.metadata(), expected.as_bytes());
+
+            // The rest of the line must not be parsed as another request
+            recv_buffers(&stream).unwrap_err();
+
+            server.join().unwrap().unwrap_err();
+        });
+
+        fs::remove_file(&path).unwrap();
+    }
+}
@@ -0,0 +1,463 @@
+use alloc::ffi::CString;
+use core::time::Duration;
+#[cfg(feature = "tokio")]
+use std::sync::OnceLock;
+use std::{
+    io,
+    os::fd::{AsFd, AsRawFd, BorrowedFd, OwnedFd, RawFd},
+};
+
+use rustix::{
+    event::PollFlags,
+    fs::{seek, SeekFrom},
+};
+#[cfg(feature = "tokio")]
+use tokio::io::{unix::AsyncFd, Interest};
+
+#[cfg(feature = "mock")]
+use crate::mock::mock_buffer_name;
+#[cfg(feature = "tokio")]
+use crate::sync_file::wait_for_interest;
+use crate::{
+    fdinfo::read_fdinfo,
+    info::is_dma_buf,
+    ioctl::{
+        dma_buf_export_sync_file, dma_buf_import_sync_file, dma_buf_set_name, DMA_BUF_NAME_LEN,
+    },
+    sync_file::wait_for_events,
+    DmaBufMapping, HeapError, HeapKind, Result, SyncAccess, SyncFile,
+};
+
+/// Returns the size of the DMA-Buf attached to the file descriptor
+pub(crate) fn dma_buf_size(fd: BorrowedFd<'_>) -> io::Result<usize> {
+    let size = seek(fd, SeekFrom::End(0))?;
+
+    usize::try_from(size).map_err(|_err| io::Error::from(io::ErrorKind::InvalidData))
+}
+
+/// Checks that a name is suitable for a DMA-Buf, and converts it to a C string
+pub(crate) fn dma_buf_name(name: &str) -> Result<CString> {
+    if name.len() >= DMA_BUF_NAME_LEN {
+        return Err(HeapError::InvalidName(name.to_owned()));
+    }
+
+    CString::new(name).map_err(|_err| HeapError::InvalidName(name.to_owned()))
+}
+
+/// A DMA-Buf, either allocated from a [`crate::Heap`] or created from a file descriptor
+///
+/// The buffer will be freed when the last reference to it goes away, including the ones held by
+/// other processes or devices.
+#[derive(Debug)]
+pub struct DmaBuf {
+    fd: OwnedFd,
+    len: usize,
+    heap: Option<HeapKind>,
+    memfd: Option<OwnedFd>,
+
+    // Buffers of the mock heap are plain memfds, without any cache maintenance to perform.
+    #[cfg(feature = "mock")]
+    mock: bool,
+
+    // A duplicate of the file descriptor registered in the tokio reactor, shared by all the
+    // asynchronous waits. It's a duplicate so that the application can still register the buffer
+    // itself.
+    #[cfg(feature = "tokio")]
+    async_fd: OnceLock<AsyncFd<OwnedFd>>,
+}
+
+impl DmaBuf {
+    pub(crate) fn new(fd: OwnedFd, len: usize, heap: Option<HeapKind>) -> Self {
+        Self {
+            fd,
+            len,
+            heap,
+            memfd: None,
+            #[cfg(feature = "mock")]
+            mock: false,
+            #[cfg(feature = "tokio")]
+            async_fd: OnceLock::new(),
+        }
+    }
+
+    pub(crate) fn with_memfd(mut self, memfd: OwnedFd) -> Self {
+        self.memfd = Some(memfd);
+        self
+    }
+
+    /// Marks the buffer as allocated by a [`crate::MockHeap`]
+    #[cfg(feature = "mock")]
+    pub(crate) fn mocked(mut self) -> Self {
+        self.mock = true;
+        self
+    }
+
+    /// Returns whether CPU accesses must be synchronized with `DMA_BUF_IOCTL_SYNC`
+    #[cfg(feature = "mock")]
+    pub(crate) fn needs_sync(&self) -> bool {
+        !self.mock
+    }
+
+    /// Returns whether CPU accesses must be synchronized with `DMA_BUF_IOCTL_SYNC`
+    #[cfg(not(feature = "mock"))]
+    #[allow(clippy::unused_self)]
+    pub(crate) fn needs_sync(&self) -> bool {
+        true
+    }
+
+    /// Imports a DMA-Buf exported by another driver or process
+    ///
+    /// Unlike the [`From<OwnedFd>`] implementation, the file descriptor is checked to be a DMA-Buf,
+    /// and its size is retrieved from the kernel.
+    ///
+    /// # Example
+    ///
+    /// ```no_run
+    /// use std::os::fd::OwnedFd;
+    ///
+    /// use dma_heap::DmaBuf;
+    ///
+    /// # fn receive_fd() -> OwnedFd { unimplemented!() }
+    /// // For example, a file descriptor exported by V4L2 through VIDIOC_EXPBUF
+    /// let fd: OwnedFd = receive_fd();
+    ///
+    /// let buffer = DmaBuf::import(fd).unwrap();
+    /// let mapping = buffer.mmap().unwrap();
+    /// ```
+    ///
+    /// # Errors
+    ///
+    /// Will return [`HeapError::NotADmaBuf`] if the file descriptor doesn't point to a DMA-Buf, or
+    /// [Error] if its size can't be retrieved.
+    pub fn import(fd: OwnedFd) -> Result<Self> {
+        if !is_dma_buf(fd.as_fd())? {
+            return Err(HeapError::NotADmaBuf);
+        }
+
+        let len = dma_buf_size(fd.as_fd())?;
+
+        Ok(Self::new(fd, len, None))
+    }
+
+    /// Returns the size of the buffer, in bytes
+    ///
+    /// This is the size reported by the kernel, and might thus be larger than the size that was
+    /// requested at allocation time due to page alignment.
+    #[must_use]
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    /// Returns true if the buffer has a size of zero
+    #[must_use]
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    /// Returns the kind of Heap the buffer has been allocated from, if known
+    #[must_use]
+    pub fn heap(&self) -> Option<&HeapKind> {
+        self.heap.as_ref()
+    }
+
+    /// Sets the debug name of the buffer
+    ///
+    /// The name is visible in `/proc/<pid>/fdinfo/<fd>` and in the kernel debugfs, and is limited
+    /// to 31 bytes. Kernels older than 5.17 will refuse to rename a buffer attached to a device.
+    ///
+    /// # Errors
+    ///
+    /// Will return [Error] if the name is too long or contains a NUL byte, or if the
+    /// `DMA_BUF_SET_NAME` ioctl fails.
+    pub fn set_name(&self, name: &str) -> Result<()> {
+        let name = dma_buf_name(name)?;
+
+        dma_buf_set_name(self.fd.as_fd(), &name)
+    }
+
+    /// Returns the debug name of the buffer, if it has one
+    ///
+    /// The buffers of a [`crate::MockHeap`] can't be renamed, and report the name set with
+    /// [`crate::AllocOptions::name`] instead.
+    ///
+    /// # Errors
+    ///
+    /// Will return [Error] if `/proc/self/fdinfo` can't be read.
+    pub fn name(&self) -> Result<Option<String>> {
+        #[cfg(feature = "mock")]
+        if self.mock {
+            return Ok(mock_buffer_name(self.fd.as_fd())?);
+        }
+
+        let name = read_fdinfo(self.fd.as_fd())?
+            .into_iter()
+            .find_map(|(key, value)| (key == "name").then_some(value))
+            .filter(|name| !name.is_empty());
+
+        Ok(name)
+    }
+
+    /// Returns the memfd backing the buffer, if any
+    ///
+    /// This is only set for the buffers created by [`crate::Udmabuf`] from a single memfd.
+    #[must_use]
+    pub fn memfd(&self) -> Option<BorrowedFd<'_>> {
+        self.memfd.as_ref().map(AsFd::as_fd)
+    }
+
+    /// Maps the buffer into the process address space
+    ///
+    /// # Example
+    ///
+    /// ```no_run
+    /// use dma_heap::{Heap, HeapKind};
+    ///
+    /// let heap = Heap::new(HeapKind::System).unwrap();
+    /// let buffer = heap.allocate(4096).unwrap();
+    /// let mut mapping = buffer.mmap().unwrap();
+    ///
+    /// // The caches are synchronized when the guard is created, and when it's dropped.
+    /// mapping.write().unwrap().fill(0xff);
+    ///
+    /// assert!(mapping.read().unwrap().iter().all(|b| *b == 0xff));
+    /// ```
+    ///
+    /// # Errors
+    ///
+    /// Will return [Error] if the `mmap()` call fails.
+    pub fn mmap(&self) -> Result<DmaBufMapping<'_>> {
+        DmaBufMapping::new(self)
+    }
+
+    /// Exports the fences currently attached to the buffer as a [`SyncFile`]
+    ///
+    /// With [`SyncAccess::Read`], the sync file will only hold the fences a reader needs to wait
+    /// for, ie. the pending writes. With [`SyncAccess::Write`] or [`SyncAccess::ReadWrite`], it
+    /// will hold all the fences.
+    ///
+    /// This requires Linux 6.0 or later.
+    ///
+    /// # Errors
+    ///
+    /// Will return [Error] if the `DMA_BUF_IOCTL_EXPORT_SYNC_FILE` ioctl fails.
+    pub fn export_sync_file(&self, access: SyncAccess) -> Result<SyncFile> {
+        let fd = dma_buf_export_sync_file(self.fd.as_fd(), access.flags())?;
+
+        Ok(SyncFile::new(fd, access))
+    }
+
+    /// Attaches the fences of a [`SyncFile`] to the buffer
+    ///
+    /// The fences will be added as read or write fences depending on [`SyncFile::access`].
+    ///
+    /// This requires Linux 6.0 or later.
+    ///
+    /// # Errors
+    ///
+    /// Will return [Error] if the `DMA_BUF_IOCTL_IMPORT_SYNC_FILE` ioctl fails.
+    pub fn import_sync_file(&self, sync_file: &SyncFile) -> Result<()> {
+        dma_buf_import_sync_file(
+            self.fd.as_fd(),
+            sync_file.as_fd(),
+            sync_file.access().flags(),
+        )
+    }
+
+    /// Waits for the buffer to be ready to be read
+    ///
+    /// A buffer is ready to be read once all the pending writes to it, by any device, have
+    /// completed. If `timeout` is `None`, this will wait forever. Returns true if the buffer is
+    /// ready, and false if the timeout expired.
+    ///
+    /// With the `tokio` feature, [`DmaBuf::readable`] waits for the same condition without
+    /// blocking the thread.
+    ///
+    /// # Errors
+    ///
+    /// Will return [Error] if the `poll()` call fails.
+    pub fn wait_readable(&self, timeout: Option<Duration>) -> Result<bool> {
+        wait_for_events(self.fd.as_fd(), PollFlags::IN, timeout)
+    }
+
+    /// Waits for the buffer to be ready to be written
+    ///
+    /// A buffer is ready to be written once all the pending accesses to it, by any device, have
+    /// completed. If `timeout` is `None`, this will wait forever. Returns true if the buffer is
+    /// ready, and false if the timeout expired.
+    ///
+    /// With the `tokio` feature, [`DmaBuf::writable`] waits for the same condition without
+    /// blocking the thread.
+    ///
+    /// # Errors
+    ///
+    /// Will return [Error] if the `poll()` call fails.
+    pub fn wait_writable(&self, timeout: Option<Duration>) -> Result<bool> {
+        wait_for_events(self.fd.as_fd(), PollFlags::OUT, timeout)
+    }
+
+    /// Waits asynchronously for the buffer to be ready to be read
+    ///
+    /// See [`DmaBuf::wait_readable`]. It must be called from within a tokio runtime, with the IO
+    /// driver enabled. The buffer is registered in the runtime on the first wait, and all the
+    /// subsequent waits must happen in the same runtime.
+    ///
+    /// # Example
+    ///
+    /// ```no_run
+    /// use dma_heap::{DmaBuf, Result};
+    ///
+    /// async fn read_frame(buffer: &DmaBuf) -> Result<Vec<u8>> {
+    ///     // Wait for the decoder to be done writing to the buffer
+    ///     buffer.readable().await?;
+    ///
+    ///     let mapping = buffer.mmap()?;
+    ///     let frame = mapping.read()?.to_vec();
+    ///
+    ///     Ok(frame)
+    /// }
+    /// ```
+    ///
+    /// # Errors
+    ///
+    /// Will return [Error] if the buffer can't be registered in the reactor, or if the `poll()`
+    /// call fails.
+    #[cfg(feature = "tokio")]
+    pub async fn readable(&self) -> Result<()> {
+        // The mock buffers are never accessed by any device, and can't be registered anyway
+        if !self.needs_sync() {
+            return Ok(());
+        }
+
+        wait_for_interest(self.async_fd()?, Interest::READABLE, PollFlags::IN).await
+    }
+
+    /// Waits asynchronously for the buffer to be ready to be written
+    ///
+    /// See [`DmaBuf::wait_writable`] and [`DmaBuf::readable`].
+    ///
+    /// # Errors
+    ///
+    /// Will return [Error] if the buffer can't be registered in the reactor, or if the `poll()`
+    /// call fails.
+    #[cfg(feature = "tokio")]
+    pub async fn writable(&self) -> Result<()> {
+        if !self.needs_sync() {
+            return Ok(());
+        }
+
+        wait_for_interest(self.async_fd()?, Interest::WRITABLE, PollFlags::OUT).await
+    }
+
+    #[cfg(feature = "tokio")]
+    fn async_fd(&self) -> Result<&AsyncFd<OwnedFd>> {
+        if let Some(fd) = self.async_fd.get() {
+            return Ok(fd);
+        }
+
+        let fd = AsyncFd::new(self.fd.try_clone()?)?;
+
+        // If another task raced us, its registration is kept and ours is dropped.
+        Ok(self.async_fd.get_or_init(|| fd))
+    }
+
+    /// Creates a new [`DmaBuf`] instance pointing to the same underlying buffer
+    ///
+    /// # Errors
+    ///
+    /// Will return [Error] if the file descriptor can't be duplicated.
+    pub fn try_clone(&self) -> Result<Self> {
+        Ok(Self {
+            fd: self.fd.try_clone()?,
+            len: self.len,
+            heap: self.heap.clone(),
+            memfd: self.memfd.as_ref().map(OwnedFd::try_clone).transpose()?,
+            #[cfg(feature = "mock")]
+            mock: self.mock,
+            #[cfg(feature = "tokio")]
+            async_fd: OnceLock::new(),
+        })
+    }
+}
+
+impl AsFd for DmaBuf {
+    fn as_fd(&self) -> BorrowedFd<'_> {
+        self.fd.as_fd()
+    }
+}
+
+impl AsRawFd for DmaBuf {
+    fn as_raw_fd(&self) -> RawFd {
+        self.fd.as_raw_fd()
+    }
+}
+
+impl From<DmaBuf> for OwnedFd {
+    fn from(buffer: DmaBuf) -> Self {
+        buffer.fd
+    }
+}
+
+impl From<OwnedFd> for DmaBuf {
+    /// Creates a [`DmaBuf`] from a file descriptor
+    ///
+    /// The file descriptor isn't checked, and if its size can't be retrieved, the buffer will be
+    /// reported as empty. Use [`DmaBuf::import`] for a checked conversion.
+    fn from(fd: OwnedFd) -> Self {
+        let len = dma_buf_size(fd.as_fd()).unwrap_or(0);
+
+        Self::new(fd, len, None)
+    }
+}
+
+#[cfg(test)]
+#[cfg(feature = "tokio")]
+mod tests {
+    use core::ptr;
+    use std::{
+        io::Write,
+        os::{fd::OwnedFd, unix::net::UnixStream},
+    };
+
+    use rustix::io::read;
+
+    use crate::DmaBuf;
+
+    #[tokio::test]
+    async fn readable() {
+        // A socket stands in for the DMA-Buf, since its readiness can be controlled
+        let (mut sender, receiver) = UnixStream::pair().unwrap();
+        let buffer = DmaBuf::from(OwnedFd::from(receiver));
+
+        let (res, ()) = tokio::join!(buffer.readable(), async {
+            sender.write_all(b"done").unwrap();
+        });
+        res.unwrap();
+
+        let registration = buffer.async_fd.get().unwrap();
+        buffer.readable().await.unwrap();
+        assert!(
+            ptr::eq(registration, buffer.async_fd.get().unwrap()),
+            "The buffer has been registered again"
+        );
+
+        // Once drained, the cached readiness must not be reported anymore
+        read(&buffer, &mut [0; 4]).unwrap();
+        tokio::select! {
+            biased;
+            _ = buffer.readable() => panic!("The buffer was reported as readable"),
+            () = tokio::task::yield_now() => {}
+        }
+    }
+
+    #[cfg(feature = "mock")]
+    #[tokio::test]
+    async fn mock_buffer_is_ready() {
+        use crate::{Heap, HeapKind, MockHeap};
+
+        let heap = Heap::mock(MockHeap::new(HeapKind::System));
+        let buffer = heap.allocate(4096).unwrap();
+
+        buffer.readable().await.unwrap();
+        buffer.writable().await.unwrap();
+    }
+}
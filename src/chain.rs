@@ -0,0 +1,127 @@
+use std::io;
+
+use log::debug;
+
+use crate::{AllocOptions, DmaBuf, DmaBufAllocator, Heap, HeapError, HeapKind, Result};
+
+/// An ordered list of DMA-Buf Heaps to allocate from
+///
+/// Allocations are tried on each Heap in order, falling back to the next one if a Heap runs out
+/// of memory. The Heap that eventually served the allocation is reported by [`DmaBuf::heap`].
+///
+/// The chain can also be built out of any [`DmaBufAllocator`] using
+/// [`HeapChain::from_allocators`].
+///
+/// # Example
+///
+/// ```no_run
+/// use dma_heap::{HeapChain, HeapKind};
+///
+/// let chain = HeapChain::new([HeapKind::Cma, HeapKind::System]).unwrap();
+/// let buffer = chain.allocate(4096).unwrap();
+///
+/// println!("Allocated from {}", buffer.heap().unwrap());
+/// ```
+#[derive(Debug)]
+pub struct HeapChain<A = Heap> {
+    heaps: Vec<A>,
+}
+
+impl HeapChain {
+    /// Opens the DMA-Buf Heaps of the chain, in order
+    ///
+    /// The Heaps that don't exist on the system, or that turn out not to be DMA-Buf Heaps, are
+    /// skipped.
+    ///
+    /// # Errors
+    ///
+    /// Will return [Error] if none of the Heaps exist, or if opening one of them fails.
+    pub fn new<I>(kinds: I) -> Result<Self>
+    where
+        I: IntoIterator<Item = HeapKind>,
+    {
+        let mut heaps = Vec::new();
+        let mut last_missing = None;
+
+        for kind in kinds {
+            match Heap::new(kind) {
+                Ok(heap) => heaps.push(heap),
+                Err(err @ (HeapError::Missing { .. } | HeapError::NotADmaHeap { .. })) => {
+                    debug!("Skipping Heap: {err}");
+                    last_missing = Some(err);
+                }
+                Err(err) => return Err(err),
+            }
+        }
+
+        if heaps.is_empty() {
+            if let Some(err) = last_missing {
+                return Err(err);
+            }
+        }
+
+        Ok(Self { heaps })
+    }
+
+    /// Returns the kinds of the Heaps in the chain, in order
+    pub fn kinds(&self) -> impl Iterator<Item = &HeapKind> {
+        self.heaps.iter().map(Heap::kind)
+    }
+}
+
+#[allow(clippy::same_name_method)]
+impl<A: DmaBufAllocator> HeapChain<A> {
+    /// Creates a chain out of a list of allocators, tried in order
+    pub fn from_allocators<I>(allocators: I) -> Self
+    where
+        I: IntoIterator<Item = A>,
+    {
+        Self {
+            heaps: allocators.into_iter().collect(),
+        }
+    }
+
+    /// Allocates a DMA-Buf with the specified size from the first Heap able to serve it
+    ///
+    /// # Errors
+    ///
+    /// Will return [Error] if all the Heaps ran out of memory, or if an allocation fails for any
+    /// other reason.
+    pub fn allocate(&self, len: usize) -> Result<DmaBuf> {
+        self.allocate_with(len, &AllocOptions::default())
+    }
+
+    /// Allocates a DMA-Buf with the specified size and options from the first Heap able to serve
+    /// it
+    ///
+    /// # Errors
+    ///
+    /// Will return the error of the last Heap if all the Heaps ran out of memory, or [Error] if
+    /// the chain is empty or if an allocation fails for any other reason.
+    pub fn allocate_with(&self, len: usize, options: &AllocOptions) -> Result<DmaBuf> {
+        let mut last_err = None;
+
+        for heap in &self.heaps {
+            match heap.allocate_with(len, options) {
+                Err(err @ HeapError::NoMemoryLeft { .. }) => {
+                    debug!("{err}, trying the next one");
+
+                    last_err = Some(err);
+                }
+                res => return res,
+            }
+        }
+
+        Err(last_err.unwrap_or_else(|| io::Error::from(io::ErrorKind::NotFound).into()))
+    }
+}
+
+impl<A: DmaBufAllocator> DmaBufAllocator for HeapChain<A> {
+    fn allocate_with(&self, len: usize, options: &AllocOptions) -> Result<DmaBuf> {
+        self.allocate_with(len, options)
+    }
+
+    fn allocate(&self, len: usize) -> Result<DmaBuf> {
+        self.allocate(len)
+    }
+}
@@ -0,0 +1,321 @@
+use std::{fs, io, path::Path};
+
+use rustix::param::page_size;
+
+use crate::Result;
+
+const MEMINFO_PATH: &str = "/proc/meminfo";
+const SYSFS_CMA_DIR: &str = "/sys/kernel/mm/cma";
+
+/// Reads a counter of a CMA area, if the kernel exposes it
+fn read_counter(dir: &Path, name: &str) -> io::Result<Option<u64>> {
+    match fs::read_to_string(dir.join(name)) {
+        Ok(content) => content
+            .trim()
+            .parse()
+            .map(Some)
+            .map_err(|_err| io::Error::from(io::ErrorKind::InvalidData)),
+        Err(err) if err.kind() == io::ErrorKind::NotFound => Ok(None),
+        Err(err) => Err(err),
+    }
+}
+
+/// Parses the CMA fields of `/proc/meminfo`, returning the total and free sizes in bytes
+fn parse_meminfo(content: &str) -> (Option<usize>, Option<usize>) {
+    let field = |name: &str| {
+        content.lines().find_map(|line| {
+            let (key, value) = line.split_once(':')?;
+            if key != name {
+                return None;
+            }
+
+            let kb = value
+                .trim()
+                .strip_suffix("kB")?
+                .trim()
+                .parse::<usize>()
+                .ok()?;
+
+            kb.checked_mul(1024)
+        })
+    };
+
+    (field("CmaTotal"), field("CmaFree"))
+}
+
+/// The counters of a CMA area, as found in `/sys/kernel/mm/cma`
+///
+/// Which counters are available depends on the kernel version.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct CmaArea {
+    name: String,
+    alloc_pages_success: Option<u64>,
+    alloc_pages_fail: Option<u64>,
+    release_pages_success: Option<u64>,
+    total_pages: Option<u64>,
+    available_pages: Option<u64>,
+}
+
+impl CmaArea {
+    fn read(dir: &Path, name: String) -> io::Result<Self> {
+        Ok(Self {
+            name,
+            alloc_pages_success: read_counter(dir, "alloc_pages_success")?,
+            alloc_pages_fail: read_counter(dir, "alloc_pages_fail")?,
+            release_pages_success: read_counter(dir, "release_pages_success")?,
+            total_pages: read_counter(dir, "total_pages")?,
+            available_pages: read_counter(dir, "available_pages")?,
+        })
+    }
+
+    /// Returns the name of the area
+    #[must_use]
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// Returns the number of pages successfully allocated from the area
+    #[must_use]
+    pub fn alloc_pages_success(&self) -> Option<u64> {
+        self.alloc_pages_success
+    }
+
+    /// Returns the number of pages the area failed to allocate
+    #[must_use]
+    pub fn alloc_pages_fail(&self) -> Option<u64> {
+        self.alloc_pages_fail
+    }
+
+    /// Returns the number of pages released to the area
+    #[must_use]
+    pub fn release_pages_success(&self) -> Option<u64> {
+        self.release_pages_success
+    }
+
+    /// Returns the size of the area, in pages
+    #[must_use]
+    pub fn total_pages(&self) -> Option<u64> {
+        self.total_pages
+    }
+
+    /// Returns the number of pages still available in the area
+    #[must_use]
+    pub fn available_pages(&self) -> Option<u64> {
+        self.available_pages
+    }
+}
+
+/// A snapshot of the state of the Contiguous Memory Allocator
+///
+/// It's attached to the [`crate::HeapError::NoMemoryLeft`] errors returned by the CMA Heap, and
+/// can be used to check whether an allocation could succeed before attempting it.
+///
+/// # Example
+///
+/// ```no_run
+/// use dma_heap::{CmaStatus, Heap, HeapKind};
+///
+/// let status = CmaStatus::read().unwrap();
+///
+/// if status.could_allocate(64 << 20) {
+///     let heap = Heap::new(HeapKind::Cma).unwrap();
+///     let buffer = heap.allocate(64 << 20).unwrap();
+/// }
+/// ```
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct CmaStatus {
+    total: Option<usize>,
+    free: Option<usize>,
+    areas: Vec<CmaArea>,
+}
+
+impl CmaStatus {
+    /// Reads the CMA state from `/proc/meminfo` and `/sys/kernel/mm/cma`
+    ///
+    /// The per-area counters are only available if the kernel has been built with
+    /// `CONFIG_CMA_SYSFS`, and are left empty otherwise.
+    ///
+    /// # Errors
+    ///
+    /// Will return [Error] if `/proc/meminfo` or the CMA areas can't be read.
+    pub fn read() -> Result<Self> {
+        let (total, free) = parse_meminfo(&fs::read_to_string(MEMINFO_PATH)?);
+
+        let mut areas = Vec::new();
+        match fs::read_dir(SYSFS_CMA_DIR) {
+            Ok(entries) => {
+                for entry in entries {
+                    let entry = entry?;
+                    let name = entry.file_name().to_string_lossy().into_owned();
+
+                    areas.push(CmaArea::read(&entry.path(), name)?);
+                }
+            }
+            Err(err) if err.kind() == io::ErrorKind::NotFound => {}
+            Err(err) => return Err(err.into()),
+        }
+
+        areas.sort_by(|a, b| a.name.cmp(&b.name));
+
+        Ok(Self { total, free, areas })
+    }
+
+    /// Returns the total size of the CMA areas, in bytes, if CMA is enabled
+    #[must_use]
+    pub fn total(&self) -> Option<usize> {
+        self.total
+    }
+
+    /// Returns the size of the free memory in the CMA areas, in bytes, if CMA is enabled
+    ///
+    /// The pages borrowed by movable allocations aren't counted as free, even though they can be
+    /// reclaimed for a CMA allocation.
+    #[must_use]
+    pub fn free(&self) -> Option<usize> {
+        self.free
+    }
+
+    /// Returns the CMA areas, sorted by name
+    #[must_use]
+    pub fn areas(&self) -> &[CmaArea] {
+        &self.areas
+    }
+
+    /// Returns the default CMA area, the one the `linux,cma` Heap allocates from
+    ///
+    /// It's named after its device tree node, usually `linux,cma`, or `reserved` if it has been
+    /// set up on the kernel command line.
+    #[must_use]
+    pub fn default_area(&self) -> Option<&CmaArea> {
+        ["linux,cma", "reserved"]
+            .iter()
+            .find_map(|name| self.areas.iter().find(|area| area.name == *name))
+    }
+
+    /// Checks whether an allocation of `len` bytes could plausibly succeed
+    ///
+    /// The allocation must fit in the available pages of the default area, see
+    /// [`CmaStatus::default_area`], if the kernel reports them. Otherwise, it must fit in the free
+    /// memory of all the CMA areas. This is conservative, since the pages lent to movable
+    /// allocations, which the kernel migrates away when needed, aren't counted as free, and
+    /// fragmentation and concurrent allocations can still make the allocation fail.
+    #[must_use]
+    pub fn could_allocate(&self, len: usize) -> bool {
+        let page_size = page_size();
+
+        let Some(len) = len
+            .checked_next_multiple_of(page_size)
+            .filter(|len| *len != 0)
+        else {
+            return false;
+        };
+
+        match self.default_area().and_then(CmaArea::available_pages) {
+            Some(available) => (len / page_size) as u64 <= available,
+            None => self.free.is_some_and(|free| len <= free),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use rustix::param::page_size;
+
+    use super::{parse_meminfo, CmaArea, CmaStatus};
+
+    fn area(name: &str, available_pages: Option<u64>) -> CmaArea {
+        CmaArea {
+            name: String::from(name),
+            alloc_pages_success: None,
+            alloc_pages_fail: None,
+            release_pages_success: None,
+            total_pages: None,
+            available_pages,
+        }
+    }
+
+    #[test]
+    fn meminfo_cma() {
+        let meminfo = "MemTotal:        3884320 kB
+MemFree:         1729412 kB
+MemAvailable:    2915236 kB
+Hugepagesize:       2048 kB
+CmaTotal:         262144 kB
+CmaFree:          196608 kB
+";
+
+        assert_eq!(parse_meminfo(meminfo), (Some(256 << 20), Some(192 << 20)));
+    }
+
+    #[test]
+    fn meminfo_without_cma() {
+        let meminfo = "MemTotal:        3884320 kB\nMemFree:         1729412 kB\n";
+
+        assert_eq!(parse_meminfo(meminfo), (None, None));
+    }
+
+    #[test]
+    fn could_allocate_from_meminfo() {
+        let status = CmaStatus {
+            total: Some(256 << 20),
+            free: Some(16 << 20),
+            areas: Vec::new(),
+        };
+
+        assert!(status.could_allocate(16 << 20), "CmaFree was ignored");
+        assert!(
+            !status.could_allocate(64 << 20),
+            "CmaTotal was used instead of CmaFree"
+        );
+        assert!(!status.could_allocate(0), "Empty allocation was accepted");
+    }
+
+    #[test]
+    fn could_allocate_from_areas() {
+        let pages = (64 << 20) / page_size() as u64;
+        let status = CmaStatus {
+            total: Some(256 << 20),
+            free: Some(16 << 20),
+            areas: vec![
+                area("camera", Some(pages * 2)),
+                area("reserved", Some(pages)),
+            ],
+        };
+
+        assert!(
+            status.could_allocate(64 << 20),
+            "The default area was ignored"
+        );
+        assert!(
+            !status.could_allocate(128 << 20),
+            "Another area than the default one was used"
+        );
+    }
+
+    #[test]
+    fn could_allocate_without_default_area() {
+        let pages = (64 << 20) / page_size() as u64;
+        let status = CmaStatus {
+            total: Some(256 << 20),
+            free: Some(16 << 20),
+            areas: vec![area("camera", Some(pages))],
+        };
+
+        assert!(
+            !status.could_allocate(64 << 20),
+            "Another area than the default one was used"
+        );
+        assert!(status.could_allocate(16 << 20), "CmaFree was ignored");
+    }
+
+    #[test]
+    fn could_allocate_without_cma() {
+        let status = CmaStatus {
+            total: None,
+            free: None,
+            areas: Vec::new(),
+        };
+
+        assert!(!status.could_allocate(4096), "CMA isn't enabled");
+    }
+}
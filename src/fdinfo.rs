@@ -0,0 +1,96 @@
+use std::{
+    fs, io,
+    os::fd::{AsRawFd, BorrowedFd, RawFd},
+};
+
+/// Reads the `/proc/self/fdinfo` entry of a file descriptor, as a list of key-value pairs
+pub(crate) fn read_fdinfo(fd: BorrowedFd<'_>) -> io::Result<Vec<(String, String)>> {
+    read_process_fdinfo("self", fd.as_raw_fd())
+}
+
+/// Reads the `/proc/<pid>/fdinfo` entry of a file descriptor, as a list of key-value pairs
+pub(crate) fn read_process_fdinfo(pid: &str, fd: RawFd) -> io::Result<Vec<(String, String)>> {
+    let content = fs::read_to_string(format!("/proc/{pid}/fdinfo/{fd}"))?;
+
+    Ok(parse_fdinfo(&content))
+}
+
+/// Checks whether the target of a `/proc/<pid>/fd` link, or the path of a `/proc/<pid>/maps`
+/// entry, is a DMA-Buf
+///
+/// DMA-Bufs are reported as `/dmabuf:<name>` since Linux 5.3, and as `anon_inode:dmabuf` before.
+pub(crate) fn is_dma_buf_path(path: &str) -> bool {
+    path.starts_with("/dmabuf:") || path == "anon_inode:dmabuf"
+}
+
+pub(crate) fn parse_fdinfo(content: &str) -> Vec<(String, String)> {
+    content
+        .lines()
+        .filter_map(|line| line.split_once(':'))
+        .map(|(key, value)| (key.trim().to_owned(), value.trim().to_owned()))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{is_dma_buf_path, parse_fdinfo};
+    use crate::DmaBufInfo;
+
+    // Captured from /proc/<pid>/fdinfo/<fd> on Linux 6.6
+    const FDINFO: &str = "pos:\t0
+flags:\t02100002
+mnt_id:\t15
+ino:\t2062
+size:\t8388608
+count:\t3
+exp_name:\tlinux,cma
+name:\tcamera-frame
+";
+
+    #[test]
+    fn fdinfo_fields() {
+        let fdinfo = parse_fdinfo(FDINFO);
+
+        assert_eq!(fdinfo.len(), 8);
+        assert_eq!(fdinfo[0], (String::from("pos"), String::from("0")));
+        assert_eq!(
+            fdinfo[6],
+            (String::from("exp_name"), String::from("linux,cma"))
+        );
+    }
+
+    #[test]
+    fn fdinfo_to_info() {
+        let info = DmaBufInfo::from_fdinfo(&parse_fdinfo(FDINFO), 2062).unwrap();
+
+        assert_eq!(info.size(), 8 << 20);
+        assert_eq!(info.exporter(), "linux,cma");
+        assert_eq!(info.name(), Some("camera-frame"));
+        assert_eq!(info.count(), 3);
+        assert_eq!(info.inode(), 2062);
+    }
+
+    #[test]
+    fn fdinfo_without_name() {
+        let fdinfo = parse_fdinfo("size:\t4096\ncount:\t1\nexp_name:\tsystem\n");
+        let info = DmaBufInfo::from_fdinfo(&fdinfo, 1).unwrap();
+
+        assert_eq!(info.name(), None);
+    }
+
+    #[test]
+    fn fdinfo_of_another_file() {
+        let fdinfo = parse_fdinfo("pos:\t0\nflags:\t02\nmnt_id:\t24\nino:\t42\n");
+
+        DmaBufInfo::from_fdinfo(&fdinfo, 42).unwrap_err();
+    }
+
+    #[test]
+    fn dma_buf_paths() {
+        assert!(is_dma_buf_path("/dmabuf:"));
+        assert!(is_dma_buf_path("/dmabuf:camera-frame"));
+        assert!(is_dma_buf_path("anon_inode:dmabuf"));
+        assert!(!is_dma_buf_path("anon_inode:[eventfd]"));
+        assert!(!is_dma_buf_path("/memfd:dma-heap-mock (deleted)"));
+    }
+}
@@ -0,0 +1,522 @@
+use strum_macros::Display;
+
+use crate::{AllocOptions, DmaBuf, DmaBufAllocator, HeapError, Result};
+
+const fn fourcc(code: [u8; 4]) -> u32 {
+    u32::from_le_bytes(code)
+}
+
+/// The layout of a plane within a pixel format
+struct PlaneFormat {
+    /// Number of bytes of a block of pixels
+    block_bytes: usize,
+
+    /// Width of a block of pixels, ie. the horizontal subsampling
+    block_width: usize,
+
+    /// The vertical subsampling
+    vsub: usize,
+}
+
+const fn plane(block_bytes: usize, block_width: usize, vsub: usize) -> PlaneFormat {
+    PlaneFormat {
+        block_bytes,
+        block_width,
+        vsub,
+    }
+}
+
+/// A pixel format, as defined by the DRM fourcc codes
+///
+/// All the formats are linear, ie. use the `DRM_FORMAT_MOD_LINEAR` modifier.
+#[derive(Clone, Copy, Debug, Display, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum PixelFormat {
+    /// 32-bit RGB, with 8 unused bits (`XR24`)
+    #[strum(to_string = "XRGB8888")]
+    Xrgb8888,
+
+    /// 32-bit RGB with an alpha channel (`AR24`)
+    #[strum(to_string = "ARGB8888")]
+    Argb8888,
+
+    /// 32-bit BGR, with 8 unused bits (`XB24`)
+    #[strum(to_string = "XBGR8888")]
+    Xbgr8888,
+
+    /// 32-bit BGR with an alpha channel (`AB24`)
+    #[strum(to_string = "ABGR8888")]
+    Abgr8888,
+
+    /// 24-bit RGB (`RG24`)
+    #[strum(to_string = "RGB888")]
+    Rgb888,
+
+    /// 16-bit RGB (`RG16`)
+    #[strum(to_string = "RGB565")]
+    Rgb565,
+
+    /// Packed 4:2:2 YCbCr (`YUYV`)
+    #[strum(to_string = "YUYV")]
+    Yuyv,
+
+    /// 4:2:0 YCbCr, with a luma plane and an interleaved Cb and Cr plane (`NV12`)
+    #[strum(to_string = "NV12")]
+    Nv12,
+
+    /// 4:2:0 YCbCr, with a luma plane and an interleaved Cr and Cb plane (`NV21`)
+    #[strum(to_string = "NV21")]
+    Nv21,
+
+    /// 4:2:2 YCbCr, with a luma plane and an interleaved Cb and Cr plane (`NV16`)
+    #[strum(to_string = "NV16")]
+    Nv16,
+
+    /// 4:2:0 YCbCr, with a plane per component (`YU12`)
+    #[strum(to_string = "YUV420")]
+    Yuv420,
+
+    /// 4:2:0 YCbCr, with a plane per component and the Cr plane first (`YV12`)
+    #[strum(to_string = "YVU420")]
+    Yvu420,
+
+    /// 4:2:2 YCbCr, with a plane per component (`YU16`)
+    #[strum(to_string = "YUV422")]
+    Yuv422,
+
+    /// 4:2:0 YCbCr with 10 bits per component stored in 16 bits, like [`PixelFormat::Nv12`]
+    /// (`P010`)
+    #[strum(to_string = "P010")]
+    P010,
+}
+
+impl PixelFormat {
+    const ALL: [Self; 14] = [
+        Self::Xrgb8888,
+        Self::Argb8888,
+        Self::Xbgr8888,
+        Self::Abgr8888,
+        Self::Rgb888,
+        Self::Rgb565,
+        Self::Yuyv,
+        Self::Nv12,
+        Self::Nv21,
+        Self::Nv16,
+        Self::Yuv420,
+        Self::Yvu420,
+        Self::Yuv422,
+        Self::P010,
+    ];
+
+    /// Returns the DRM fourcc code of the format
+    #[must_use]
+    pub const fn fourcc(self) -> u32 {
+        match self {
+            Self::Xrgb8888 => fourcc(*b"XR24"),
+            Self::Argb8888 => fourcc(*b"AR24"),
+            Self::Xbgr8888 => fourcc(*b"XB24"),
+            Self::Abgr8888 => fourcc(*b"AB24"),
+            Self::Rgb888 => fourcc(*b"RG24"),
+            Self::Rgb565 => fourcc(*b"RG16"),
+            Self::Yuyv => fourcc(*b"YUYV"),
+            Self::Nv12 => fourcc(*b"NV12"),
+            Self::Nv21 => fourcc(*b"NV21"),
+            Self::Nv16 => fourcc(*b"NV16"),
+            Self::Yuv420 => fourcc(*b"YU12"),
+            Self::Yvu420 => fourcc(*b"YV12"),
+            Self::Yuv422 => fourcc(*b"YU16"),
+            Self::P010 => fourcc(*b"P010"),
+        }
+    }
+
+    /// Returns the format matching a DRM fourcc code, if it's supported
+    #[must_use]
+    pub fn from_fourcc(code: u32) -> Option<Self> {
+        Self::ALL.into_iter().find(|format| format.fourcc() == code)
+    }
+
+    /// Returns the number of planes of the format
+    #[must_use]
+    pub fn num_planes(self) -> usize {
+        self.planes().len()
+    }
+
+    fn planes(self) -> &'static [PlaneFormat] {
+        const RGB32: &[PlaneFormat] = &[plane(4, 1, 1)];
+        const RGB24: &[PlaneFormat] = &[plane(3, 1, 1)];
+        const RGB16: &[PlaneFormat] = &[plane(2, 1, 1)];
+        const PACKED_422: &[PlaneFormat] = &[plane(4, 2, 1)];
+        const SEMI_PLANAR_420: &[PlaneFormat] = &[plane(1, 1, 1), plane(2, 2, 2)];
+        const SEMI_PLANAR_422: &[PlaneFormat] = &[plane(1, 1, 1), plane(2, 2, 1)];
+        const PLANAR_420: &[PlaneFormat] = &[plane(1, 1, 1), plane(1, 2, 2), plane(1, 2, 2)];
+        const PLANAR_422: &[PlaneFormat] = &[plane(1, 1, 1), plane(1, 2, 1), plane(1, 2, 1)];
+        const SEMI_PLANAR_420_16: &[PlaneFormat] = &[plane(2, 1, 1), plane(4, 2, 2)];
+
+        match self {
+            Self::Xrgb8888 | Self::Argb8888 | Self::Xbgr8888 | Self::Abgr8888 => RGB32,
+            Self::Rgb888 => RGB24,
+            Self::Rgb565 => RGB16,
+            Self::Yuyv => PACKED_422,
+            Self::Nv12 | Self::Nv21 => SEMI_PLANAR_420,
+            Self::Nv16 => SEMI_PLANAR_422,
+            Self::Yuv420 | Self::Yvu420 => PLANAR_420,
+            Self::Yuv422 => PLANAR_422,
+            Self::P010 => SEMI_PLANAR_420_16,
+        }
+    }
+}
+
+/// The location of a plane of an [`Image`]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct ImagePlane {
+    buffer: usize,
+    offset: usize,
+    pitch: usize,
+    size: usize,
+}
+
+impl ImagePlane {
+    /// Returns the index, in [`Image::buffers`], of the buffer holding the plane
+    #[must_use]
+    pub fn buffer(&self) -> usize {
+        self.buffer
+    }
+
+    /// Returns the offset of the plane in its buffer, in bytes
+    #[must_use]
+    pub fn offset(&self) -> usize {
+        self.offset
+    }
+
+    /// Returns the number of bytes between the start of two consecutive lines
+    #[must_use]
+    pub fn pitch(&self) -> usize {
+        self.pitch
+    }
+
+    /// Returns the size of the plane, in bytes
+    #[must_use]
+    pub fn size(&self) -> usize {
+        self.size
+    }
+}
+
+/// Options to describe an [`Image`] allocation
+///
+/// # Example
+///
+/// ```
+/// use dma_heap::{ImageOptions, PixelFormat};
+///
+/// let options = ImageOptions::new(PixelFormat::Nv12, 1920, 1080)
+///     .stride_alignment(256)
+///     .height_alignment(16);
+///
+/// let planes = options.planes().unwrap();
+/// assert_eq!(planes[0].pitch(), 2048);
+/// assert_eq!(planes[1].offset(), 2048 * 1088);
+/// ```
+#[derive(Clone, Debug)]
+pub struct ImageOptions {
+    format: PixelFormat,
+    width: u32,
+    height: u32,
+    stride_alignment: usize,
+    height_alignment: u32,
+    separate_planes: bool,
+    alloc: AllocOptions,
+}
+
+impl ImageOptions {
+    /// Creates the options for an image of the given format and size, in pixels
+    ///
+    /// By default, the lines and planes are tightly packed, and all the planes share a single
+    /// buffer.
+    #[must_use]
+    pub fn new(format: PixelFormat, width: u32, height: u32) -> Self {
+        Self {
+            format,
+            width,
+            height,
+            stride_alignment: 1,
+            height_alignment: 1,
+            separate_planes: false,
+            alloc: AllocOptions::default(),
+        }
+    }
+
+    /// Sets the alignment, in bytes, of the pitch of the first plane
+    ///
+    /// The pitches of the other planes are derived from it, following their subsampling, as V4L2
+    /// expects.
+    #[must_use]
+    pub fn stride_alignment(mut self, bytes: usize) -> Self {
+        self.stride_alignment = bytes;
+        self
+    }
+
+    /// Sets the alignment, in lines, of the height of the image
+    #[must_use]
+    pub fn height_alignment(mut self, lines: u32) -> Self {
+        self.height_alignment = lines;
+        self
+    }
+
+    /// Sets whether each plane is allocated in its own buffer
+    #[must_use]
+    pub fn separate_planes(mut self, separate: bool) -> Self {
+        self.separate_planes = separate;
+        self
+    }
+
+    /// Sets the options used to allocate the buffers
+    #[must_use]
+    pub fn alloc_options(mut self, options: AllocOptions) -> Self {
+        self.alloc = options;
+        self
+    }
+
+    fn invalid(&self) -> HeapError {
+        HeapError::InvalidImage {
+            format: self.format,
+            width: self.width,
+            height: self.height,
+        }
+    }
+
+    /// Computes the layout of the planes of the image
+    ///
+    /// # Errors
+    ///
+    /// Will return [Error] if the size or alignments are zero, or if the image is too large.
+    pub fn planes(&self) -> Result<Vec<ImagePlane>> {
+        if self.width == 0
+            || self.height == 0
+            || self.stride_alignment == 0
+            || self.height_alignment == 0
+        {
+            return Err(self.invalid());
+        }
+
+        let width = self.width as usize;
+        let height = self
+            .height
+            .checked_next_multiple_of(self.height_alignment)
+            .ok_or_else(|| self.invalid())? as usize;
+
+        let formats = self.format.planes();
+        let [first, ..] = formats else {
+            return Err(self.invalid());
+        };
+
+        let first_pitch = width
+            .div_ceil(first.block_width)
+            .checked_mul(first.block_bytes)
+            .and_then(|pitch| pitch.checked_next_multiple_of(self.stride_alignment))
+            .ok_or_else(|| self.invalid())?;
+
+        let mut planes = Vec::with_capacity(formats.len());
+        let mut offset = 0usize;
+
+        for (index, format) in formats.iter().enumerate() {
+            // The planes share the same number of bytes per pixel ratio as the first one, but
+            // odd widths round the subsampled planes up, so that ratio alone might fall short.
+            let min_pitch = width
+                .div_ceil(format.block_width)
+                .checked_mul(format.block_bytes)
+                .ok_or_else(|| self.invalid())?;
+
+            let pitch = first_pitch
+                .checked_mul(format.block_bytes * first.block_width)
+                .ok_or_else(|| self.invalid())?
+                .div_ceil(format.block_width * first.block_bytes)
+                .max(min_pitch);
+
+            let size = pitch
+                .checked_mul(height.div_ceil(format.vsub))
+                .ok_or_else(|| self.invalid())?;
+
+            let (buffer, plane_offset) = if self.separate_planes {
+                (index, 0)
+            } else {
+                (0, offset)
+            };
+
+            offset = offset.checked_add(size).ok_or_else(|| self.invalid())?;
+
+            planes.push(ImagePlane {
+                buffer,
+                offset: plane_offset,
+                pitch,
+                size,
+            });
+        }
+
+        Ok(planes)
+    }
+}
+
+/// A multi-planar image, made of one or more DMA-Bufs
+///
+/// It holds everything needed to import the image into KMS, V4L2 or Wayland: the buffers, along
+/// with the offset and pitch of each plane.
+///
+/// # Example
+///
+/// ```no_run
+/// use dma_heap::{Heap, HeapKind, Image, ImageOptions, PixelFormat};
+///
+/// let heap = Heap::new(HeapKind::Cma).unwrap();
+///
+/// let options = ImageOptions::new(PixelFormat::Nv12, 1920, 1080).stride_alignment(64);
+/// let image = Image::allocate(&heap, &options).unwrap();
+///
+/// for plane in image.planes() {
+///     let buffer = &image.buffers()[plane.buffer()];
+///     println!(
+///         "{} bytes, offset {}, pitch {}",
+///         buffer.len(),
+///         plane.offset(),
+///         plane.pitch()
+///     );
+/// }
+/// ```
+#[derive(Debug)]
+pub struct Image {
+    format: PixelFormat,
+    width: u32,
+    height: u32,
+    buffers: Vec<DmaBuf>,
+    planes: Vec<ImagePlane>,
+}
+
+impl Image {
+    /// Allocates an image from an allocator, according to the given options
+    ///
+    /// # Errors
+    ///
+    /// Will return [Error] if the image layout is invalid, or if an allocation fails.
+    pub fn allocate<A: DmaBufAllocator>(allocator: &A, options: &ImageOptions) -> Result<Self> {
+        let planes = options.planes()?;
+
+        let mut sizes = Vec::<usize>::new();
+        for plane in &planes {
+            let end = plane.offset + plane.size;
+
+            match sizes.get_mut(plane.buffer) {
+                Some(size) => *size = (*size).max(end),
+                None => sizes.push(end),
+            }
+        }
+
+        let buffers = sizes
+            .into_iter()
+            .map(|size| allocator.allocate_with(size, &options.alloc))
+            .collect::<Result<Vec<_>>>()?;
+
+        Ok(Self {
+            format: options.format,
+            width: options.width,
+            height: options.height,
+            buffers,
+            planes,
+        })
+    }
+
+    /// Returns the pixel format of the image
+    #[must_use]
+    pub fn format(&self) -> PixelFormat {
+        self.format
+    }
+
+    /// Returns the width of the image, in pixels
+    #[must_use]
+    pub fn width(&self) -> u32 {
+        self.width
+    }
+
+    /// Returns the height of the image, in pixels, without the alignment
+    #[must_use]
+    pub fn height(&self) -> u32 {
+        self.height
+    }
+
+    /// Returns the buffers backing the image
+    #[must_use]
+    pub fn buffers(&self) -> &[DmaBuf] {
+        &self.buffers
+    }
+
+    /// Returns the planes of the image
+    #[must_use]
+    pub fn planes(&self) -> &[ImagePlane] {
+        &self.planes
+    }
+
+    /// Consumes the image, returning its buffers
+    #[must_use]
+    pub fn into_buffers(self) -> Vec<DmaBuf> {
+        self.buffers
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{ImageOptions, PixelFormat};
+
+    #[test]
+    fn odd_sizes() {
+        for format in PixelFormat::ALL {
+            for (width, height) in [(1, 1), (3, 3), (1919, 1079)] {
+                let planes = ImageOptions::new(format, width, height).planes().unwrap();
+                assert_eq!(planes.len(), format.num_planes());
+
+                for (plane, layout) in planes.iter().zip(format.planes()) {
+                    let width = width as usize;
+                    let lines = (height as usize).div_ceil(layout.vsub);
+                    let min_pitch = width.div_ceil(layout.block_width) * layout.block_bytes;
+
+                    assert!(
+                        plane.pitch() >= min_pitch,
+                        "{format} {width}x{height}: pitch {} is too small",
+                        plane.pitch()
+                    );
+                    assert_eq!(plane.size(), plane.pitch() * lines);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn odd_chroma_pitches() {
+        for (format, pitches) in [
+            (PixelFormat::Nv12, [1919, 1920, 0]),
+            (PixelFormat::Nv21, [1919, 1920, 0]),
+            (PixelFormat::Nv16, [1919, 1920, 0]),
+            (PixelFormat::P010, [3838, 3840, 0]),
+            (PixelFormat::Yuv420, [1919, 960, 960]),
+            (PixelFormat::Yuv422, [1919, 960, 960]),
+            (PixelFormat::Yuyv, [3840, 0, 0]),
+        ] {
+            let planes = ImageOptions::new(format, 1919, 1079).planes().unwrap();
+
+            for (index, plane) in planes.iter().enumerate() {
+                assert_eq!(plane.pitch(), pitches[index], "{format} plane {index}");
+            }
+        }
+    }
+
+    #[test]
+    fn aligned_pitches() {
+        let planes = ImageOptions::new(PixelFormat::Yuv420, 1919, 1079)
+            .stride_alignment(256)
+            .planes()
+            .unwrap();
+
+        assert_eq!(planes[0].pitch(), 2048);
+        assert_eq!(planes[1].pitch(), 1024);
+        assert_eq!(planes[2].pitch(), 1024);
+        assert_eq!(planes[1].offset(), 2048 * 1079);
+        assert_eq!(planes[2].offset(), 2048 * 1079 + 1024 * 540);
+    }
+}
@@ -0,0 +1,149 @@
+use std::{
+    fs, io,
+    os::{
+        fd::{AsFd, BorrowedFd, RawFd},
+        unix::fs::MetadataExt,
+    },
+};
+
+use rustix::fs::{fstat, fstatfs};
+
+use crate::{
+    fdinfo::{is_dma_buf_path, read_fdinfo, read_process_fdinfo},
+    HeapError, Result,
+};
+
+/// Magic number of the DMA-Buf pseudo-filesystem
+const DMA_BUF_MAGIC: u64 = 0x444d_4142;
+
+/// Checks whether a file descriptor points to a DMA-Buf
+pub(crate) fn is_dma_buf(fd: BorrowedFd<'_>) -> Result<bool> {
+    let statfs = fstatfs(fd).map_err(io::Error::from)?;
+
+    Ok(u64::try_from(statfs.f_type).is_ok_and(|magic| magic == DMA_BUF_MAGIC))
+}
+
+/// Information about a DMA-Buf, as reported by the kernel
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct DmaBufInfo {
+    size: usize,
+    exporter: String,
+    name: Option<String>,
+    inode: u64,
+    count: u64,
+}
+
+impl DmaBufInfo {
+    /// Queries the kernel about the DMA-Buf attached to a file descriptor
+    ///
+    /// The file descriptor can point to any DMA-Buf, including one received from another process
+    /// or exported by another driver.
+    ///
+    /// # Example
+    ///
+    /// ```no_run
+    /// use dma_heap::{DmaBufInfo, Heap, HeapKind};
+    ///
+    /// let heap = Heap::new(HeapKind::System).unwrap();
+    /// let buffer = heap.allocate(4096).unwrap();
+    ///
+    /// let info = DmaBufInfo::from_fd(&buffer).unwrap();
+    /// assert_eq!(info.exporter(), "system");
+    /// ```
+    ///
+    /// # Errors
+    ///
+    /// Will return [`HeapError::NotADmaBuf`] if the file descriptor doesn't point to a DMA-Buf, or
+    /// [Error] if `/proc/self/fdinfo` can't be read or parsed.
+    pub fn from_fd<F: AsFd>(fd: F) -> Result<Self> {
+        let fd = fd.as_fd();
+
+        if !is_dma_buf(fd)? {
+            return Err(HeapError::NotADmaBuf);
+        }
+
+        let inode = fstat(fd).map_err(io::Error::from)?.st_ino;
+        let fdinfo = read_fdinfo(fd)?;
+
+        Self::from_fdinfo(&fdinfo, inode)
+    }
+
+    /// Queries the kernel about a DMA-Buf held by a process
+    ///
+    /// Accessing the file descriptors of another process usually requires to run as the same
+    /// user, or to be privileged.
+    ///
+    /// # Errors
+    ///
+    /// Will return [`HeapError::NotADmaBuf`] if the file descriptor doesn't point to a DMA-Buf, or
+    /// [Error] if `/proc/<pid>/fd` or `/proc/<pid>/fdinfo` can't be read or parsed.
+    pub fn from_pid_fd(pid: u32, fd: RawFd) -> Result<Self> {
+        let fd_path = format!("/proc/{pid}/fd/{fd}");
+
+        if !is_dma_buf_path(&fs::read_link(&fd_path)?.to_string_lossy()) {
+            return Err(HeapError::NotADmaBuf);
+        }
+
+        let inode = fs::metadata(&fd_path)?.ino();
+        let fdinfo = read_process_fdinfo(&pid.to_string(), fd)?;
+
+        Self::from_fdinfo(&fdinfo, inode)
+    }
+
+    pub(crate) fn from_fdinfo(fdinfo: &[(String, String)], inode: u64) -> Result<Self> {
+        let field = |name: &str| {
+            fdinfo
+                .iter()
+                .find_map(|(key, value)| (key == name).then_some(value.as_str()))
+        };
+
+        let invalid = || io::Error::from(io::ErrorKind::InvalidData);
+
+        Ok(Self {
+            size: field("size")
+                .and_then(|size| size.parse().ok())
+                .ok_or_else(invalid)?,
+            exporter: field("exp_name").ok_or_else(invalid)?.to_owned(),
+            name: field("name")
+                .filter(|name| !name.is_empty())
+                .map(str::to_owned),
+            inode,
+            count: field("count")
+                .and_then(|count| count.parse().ok())
+                .ok_or_else(invalid)?,
+        })
+    }
+
+    /// Returns the size of the buffer, in bytes
+    #[must_use]
+    pub fn size(&self) -> usize {
+        self.size
+    }
+
+    /// Returns the name of the exporter of the buffer, ie. `system` or `linux,cma` for the
+    /// DMA-Buf Heaps
+    #[must_use]
+    pub fn exporter(&self) -> &str {
+        &self.exporter
+    }
+
+    /// Returns the debug name of the buffer, if any
+    #[must_use]
+    pub fn name(&self) -> Option<&str> {
+        self.name.as_deref()
+    }
+
+    /// Returns the inode number of the buffer, unique across the system
+    #[must_use]
+    pub fn inode(&self) -> u64 {
+        self.inode
+    }
+
+    /// Returns the number of references to the buffer file
+    ///
+    /// Each file descriptor and memory mapping, in any process, holds a reference.
+    #[must_use]
+    pub fn count(&self) -> u64 {
+        self.count
+    }
+}
@@ -1,12 +1,20 @@
+use core::{
+    ffi::{c_char, c_void, CStr},
+    marker::PhantomData,
+};
 use std::{
     io,
-    os::fd::{BorrowedFd, FromRawFd, OwnedFd, RawFd},
+    os::fd::{AsRawFd, BorrowedFd, FromRawFd, OwnedFd},
+    path::Path,
 };
 
 use rustix::{
     fs::OFlags,
     io::Errno,
-    ioctl::{ioctl, ReadWriteOpcode, Updater},
+    ioctl::{
+        ioctl, CompileTimeOpcode, IntegerSetter, Ioctl, IoctlOutput, Opcode, ReadWriteOpcode,
+        Setter, Updater, WriteOpcode,
+    },
 };
 
 use crate::{HeapError, Result};
@@ -14,6 +22,33 @@ use crate::{HeapError, Result};
 const DMA_HEAP_IOC_MAGIC: u8 = b'H';
 const DMA_HEAP_IOC_ALLOC: u8 = 0;
 
+const DMA_BUF_BASE: u8 = b'b';
+const DMA_BUF_IOCTL_SYNC: u8 = 0;
+const DMA_BUF_SET_NAME: u8 = 1;
+const DMA_BUF_IOCTL_EXPORT_SYNC_FILE: u8 = 2;
+const DMA_BUF_IOCTL_IMPORT_SYNC_FILE: u8 = 3;
+
+const SYNC_IOC_MAGIC: u8 = b'>';
+const SYNC_IOC_MERGE: u8 = 3;
+const SYNC_IOC_FILE_INFO: u8 = 4;
+
+pub(crate) const SYNC_NAME_LEN: usize = 32;
+
+const UDMABUF_BASE: u8 = b'u';
+const UDMABUF_CREATE: u8 = 0x42;
+const UDMABUF_CREATE_LIST: u8 = 0x43;
+
+const UDMABUF_FLAGS_CLOEXEC: u32 = 0x01;
+
+/// Maximum length of a DMA-Buf name, including the trailing NUL byte
+pub(crate) const DMA_BUF_NAME_LEN: usize = 32;
+
+pub(crate) const DMA_BUF_SYNC_READ: u32 = 1;
+pub(crate) const DMA_BUF_SYNC_WRITE: u32 = 2;
+pub(crate) const DMA_BUF_SYNC_RW: u32 = DMA_BUF_SYNC_READ | DMA_BUF_SYNC_WRITE;
+pub(crate) const DMA_BUF_SYNC_START: u32 = 0;
+pub(crate) const DMA_BUF_SYNC_END: u32 = 1 << 2;
+
 #[derive(Default)]
 #[repr(C)]
 struct dma_heap_allocation_data {
@@ -39,29 +74,438 @@ fn dma_heap_alloc_ioctl(
     unsafe { ioctl(fd, ioctl_type) }
 }
 
-pub(crate) fn dma_heap_alloc(fd: BorrowedFd<'_>, len: usize) -> Result<OwnedFd> {
-    let mut fd_flags = OFlags::empty();
+/// Converts an error returned by the `DMA_HEAP_IOCTL_ALLOC` ioctl into our error type
+pub(crate) fn dma_heap_alloc_error(
+    err: Errno,
+    path: &Path,
+    len: usize,
+    heap_flags: u64,
+) -> HeapError {
+    let path = path.to_path_buf();
+    let errno = err.raw_os_error();
+
+    match err {
+        Errno::INVAL if heap_flags != 0 => HeapError::InvalidHeapFlags {
+            path,
+            flags: heap_flags,
+            errno,
+        },
+        Errno::INVAL => HeapError::InvalidAllocation { path, len, errno },
+        Errno::NOMEM => HeapError::NoMemoryLeft {
+            path,
+            errno,
+            cma: None,
+        },
+        Errno::ACCESS | Errno::PERM => HeapError::PermissionDenied { path, errno },
+        Errno::NOTTY => HeapError::NotADmaHeap { path, errno },
+        Errno::INTR => HeapError::Interrupted { path, errno },
+        _ => HeapError::Access {
+            path: Some(path),
+            source: io::Error::from_raw_os_error(errno),
+        },
+    }
+}
+
+/// Checks whether a device implements the `DMA_HEAP_IOCTL_ALLOC` ioctl
+///
+/// The DMA-Buf Heaps reject empty allocations with `EINVAL`, while the other devices don't know
+/// about the ioctl and return `ENOTTY`.
+pub(crate) fn dma_heap_probe(fd: BorrowedFd<'_>) -> bool {
+    let mut data = dma_heap_allocation_data {
+        fd_flags: (OFlags::RDWR | OFlags::CLOEXEC).bits(),
+        ..dma_heap_allocation_data::default()
+    };
+
+    match dma_heap_alloc_ioctl(fd, &mut data) {
+        Ok(()) => {
+            // SAFETY: This function is unsafe because the file descriptor might not be valid.
+            // However, the kernel has just given it to us, and we're closing it right away.
+            drop(unsafe { OwnedFd::from_raw_fd(data.fd.cast_signed()) });
 
-    fd_flags.insert(OFlags::CLOEXEC);
-    fd_flags.insert(OFlags::RDWR);
+            true
+        }
+        Err(err) => err == Errno::INVAL,
+    }
+}
 
+pub(crate) fn dma_heap_alloc(
+    fd: BorrowedFd<'_>,
+    path: &Path,
+    len: usize,
+    fd_flags: OFlags,
+    heap_flags: u64,
+) -> Result<OwnedFd> {
     let mut data = dma_heap_allocation_data {
         len: len as u64,
         fd_flags: fd_flags.bits(),
+        heap_flags,
         ..dma_heap_allocation_data::default()
     };
 
-    dma_heap_alloc_ioctl(fd, &mut data).map_err(|err| match err {
-        Errno::INVAL => HeapError::InvalidAllocation(len),
-        Errno::NOMEM => HeapError::NoMemoryLeft,
-        _ => io::Error::from_raw_os_error(err.raw_os_error()).into(),
-    })?;
+    dma_heap_alloc_ioctl(fd, &mut data)
+        .map_err(|err| dma_heap_alloc_error(err, path, len, heap_flags))?;
 
     // SAFETY: This function is unsafe because the file descriptor might not be valid, might
     // have been closed, or we might not be the sole owners of it. However, they are all
     // mitigated by the fact that the kernel has just given us that file descriptor so it's
     // valid, we are the exclusive owner of that fd, and we haven't closed it either.
-    let fd = unsafe { OwnedFd::from_raw_fd(data.fd as RawFd) };
+    let fd = unsafe { OwnedFd::from_raw_fd(data.fd.cast_signed()) };
 
     Ok(fd)
 }
+
+#[repr(C)]
+struct dma_buf_sync {
+    flags: u64,
+}
+
+fn dma_buf_sync_ioctl(fd: BorrowedFd<'_>, flags: u32) -> core::result::Result<(), Errno> {
+    type Opcode = WriteOpcode<DMA_BUF_BASE, DMA_BUF_IOCTL_SYNC, dma_buf_sync>;
+
+    // SAFETY: This function is unsafe because the opcode has to be valid, and the value type must
+    // match. We have checked those, so we're good.
+    let ioctl_type = unsafe {
+        Setter::<Opcode, dma_buf_sync>::new(dma_buf_sync {
+            flags: u64::from(flags),
+        })
+    };
+
+    // SAFETY: This function is unsafe because the driver isn't guaranteed to implement the ioctl,
+    // and to implement it properly. We don't have much of a choice and still have to trust the
+    // kernel there.
+    unsafe { ioctl(fd, ioctl_type) }
+}
+
+pub(crate) fn dma_buf_sync(fd: BorrowedFd<'_>, flags: u32) -> Result<()> {
+    loop {
+        match dma_buf_sync_ioctl(fd, flags) {
+            Ok(()) => return Ok(()),
+            Err(Errno::INTR | Errno::AGAIN) => {}
+            Err(err) => return Err(io::Error::from_raw_os_error(err.raw_os_error()).into()),
+        }
+    }
+}
+
+pub(crate) fn dma_buf_set_name(fd: BorrowedFd<'_>, name: &CStr) -> Result<()> {
+    type Opcode = WriteOpcode<DMA_BUF_BASE, DMA_BUF_SET_NAME, *const c_char>;
+
+    // SAFETY: This function is unsafe because the opcode has to be valid, and the value must be
+    // what the ioctl expects. DMA_BUF_SET_NAME takes the pointer to the string itself, and name
+    // outlives the ioctl call.
+    let ioctl_type = unsafe { IntegerSetter::<Opcode>::new(name.as_ptr().expose_provenance()) };
+
+    // SAFETY: This function is unsafe because the driver isn't guaranteed to implement the ioctl,
+    // and to implement it properly. We don't have much of a choice and still have to trust the
+    // kernel there.
+    unsafe { ioctl(fd, ioctl_type) }
+        .map_err(|err| io::Error::from_raw_os_error(err.raw_os_error()).into())
+}
+
+#[derive(Default)]
+#[repr(C)]
+struct dma_buf_export_sync_file {
+    flags: u32,
+    fd: i32,
+}
+
+pub(crate) fn dma_buf_export_sync_file(fd: BorrowedFd<'_>, flags: u32) -> Result<OwnedFd> {
+    type Opcode =
+        ReadWriteOpcode<DMA_BUF_BASE, DMA_BUF_IOCTL_EXPORT_SYNC_FILE, dma_buf_export_sync_file>;
+
+    let mut data = dma_buf_export_sync_file {
+        flags,
+        ..dma_buf_export_sync_file::default()
+    };
+
+    // SAFETY: This function is unsafe because the opcode has to be valid, and the value type must
+    // match. We have checked those, so we're good.
+    let ioctl_type = unsafe { Updater::<Opcode, dma_buf_export_sync_file>::new(&mut data) };
+
+    // SAFETY: This function is unsafe because the driver isn't guaranteed to implement the ioctl,
+    // and to implement it properly. We don't have much of a choice and still have to trust the
+    // kernel there.
+    unsafe { ioctl(fd, ioctl_type) }
+        .map_err(|err| io::Error::from_raw_os_error(err.raw_os_error()))?;
+
+    // SAFETY: This function is unsafe because the file descriptor might not be valid, might
+    // have been closed, or we might not be the sole owners of it. However, they are all
+    // mitigated by the fact that the kernel has just given us that file descriptor so it's
+    // valid, we are the exclusive owner of that fd, and we haven't closed it either.
+    let fd = unsafe { OwnedFd::from_raw_fd(data.fd) };
+
+    Ok(fd)
+}
+
+#[repr(C)]
+struct dma_buf_import_sync_file {
+    flags: u32,
+    fd: i32,
+}
+
+pub(crate) fn dma_buf_import_sync_file(
+    fd: BorrowedFd<'_>,
+    sync_file: BorrowedFd<'_>,
+    flags: u32,
+) -> Result<()> {
+    type Opcode =
+        WriteOpcode<DMA_BUF_BASE, DMA_BUF_IOCTL_IMPORT_SYNC_FILE, dma_buf_import_sync_file>;
+
+    let data = dma_buf_import_sync_file {
+        flags,
+        fd: sync_file.as_raw_fd(),
+    };
+
+    // SAFETY: This function is unsafe because the opcode has to be valid, and the value type must
+    // match. We have checked those, so we're good.
+    let ioctl_type = unsafe { Setter::<Opcode, dma_buf_import_sync_file>::new(data) };
+
+    // SAFETY: This function is unsafe because the driver isn't guaranteed to implement the ioctl,
+    // and to implement it properly. We don't have much of a choice and still have to trust the
+    // kernel there.
+    unsafe { ioctl(fd, ioctl_type) }
+        .map_err(|err| io::Error::from_raw_os_error(err.raw_os_error()).into())
+}
+
+#[derive(Default)]
+#[repr(C)]
+struct sync_merge_data {
+    name: [u8; SYNC_NAME_LEN],
+    fd2: i32,
+    fence: i32,
+    flags: u32,
+    pad: u32,
+}
+
+pub(crate) fn sync_file_merge(
+    fd: BorrowedFd<'_>,
+    other: BorrowedFd<'_>,
+    name: [u8; SYNC_NAME_LEN],
+) -> Result<OwnedFd> {
+    type Opcode = ReadWriteOpcode<SYNC_IOC_MAGIC, SYNC_IOC_MERGE, sync_merge_data>;
+
+    let mut data = sync_merge_data {
+        name,
+        fd2: other.as_raw_fd(),
+        ..sync_merge_data::default()
+    };
+
+    // SAFETY: This function is unsafe because the opcode has to be valid, and the value type must
+    // match. We have checked those, so we're good.
+    let ioctl_type = unsafe { Updater::<Opcode, sync_merge_data>::new(&mut data) };
+
+    // SAFETY: This function is unsafe because the driver isn't guaranteed to implement the ioctl,
+    // and to implement it properly. We don't have much of a choice and still have to trust the
+    // kernel there.
+    unsafe { ioctl(fd, ioctl_type) }
+        .map_err(|err| io::Error::from_raw_os_error(err.raw_os_error()))?;
+
+    // SAFETY: This function is unsafe because the file descriptor might not be valid, might
+    // have been closed, or we might not be the sole owners of it. However, they are all
+    // mitigated by the fact that the kernel has just given us that file descriptor so it's
+    // valid, we are the exclusive owner of that fd, and we haven't closed it either.
+    let fd = unsafe { OwnedFd::from_raw_fd(data.fence) };
+
+    Ok(fd)
+}
+
+#[derive(Clone, Copy)]
+#[repr(C)]
+pub(crate) struct sync_fence_info {
+    pub(crate) obj_name: [u8; SYNC_NAME_LEN],
+    pub(crate) driver_name: [u8; SYNC_NAME_LEN],
+    pub(crate) status: i32,
+    pub(crate) flags: u32,
+    pub(crate) timestamp_ns: u64,
+}
+
+impl Default for sync_fence_info {
+    fn default() -> Self {
+        Self {
+            obj_name: [0; SYNC_NAME_LEN],
+            driver_name: [0; SYNC_NAME_LEN],
+            status: 0,
+            flags: 0,
+            timestamp_ns: 0,
+        }
+    }
+}
+
+#[derive(Default)]
+#[repr(C)]
+pub(crate) struct sync_file_info {
+    pub(crate) name: [u8; SYNC_NAME_LEN],
+    pub(crate) status: i32,
+    pub(crate) flags: u32,
+    pub(crate) num_fences: u32,
+    pub(crate) pad: u32,
+    pub(crate) sync_fence_info: u64,
+}
+
+fn sync_file_info_ioctl(fd: BorrowedFd<'_>, data: &mut sync_file_info) -> Result<()> {
+    type Opcode = ReadWriteOpcode<SYNC_IOC_MAGIC, SYNC_IOC_FILE_INFO, sync_file_info>;
+
+    // SAFETY: This function is unsafe because the opcode has to be valid, and the value type must
+    // match. We have checked those, and the caller makes sure the fences array pointer, if any,
+    // is valid for num_fences entries.
+    let ioctl_type = unsafe { Updater::<Opcode, sync_file_info>::new(data) };
+
+    // SAFETY: This function is unsafe because the driver isn't guaranteed to implement the ioctl,
+    // and to implement it properly. We don't have much of a choice and still have to trust the
+    // kernel there.
+    unsafe { ioctl(fd, ioctl_type) }
+        .map_err(|err| io::Error::from_raw_os_error(err.raw_os_error()).into())
+}
+
+pub(crate) fn sync_file_info(fd: BorrowedFd<'_>) -> Result<(sync_file_info, Vec<sync_fence_info>)> {
+    // We first need to retrieve the number of fences, and then the fences themselves.
+    let mut info = sync_file_info::default();
+    sync_file_info_ioctl(fd, &mut info)?;
+
+    let num_fences = usize::try_from(info.num_fences)
+        .map_err(|_err| io::Error::from(io::ErrorKind::InvalidData))?;
+    let mut fences = vec![sync_fence_info::default(); num_fences];
+    if fences.is_empty() {
+        return Ok((info, fences));
+    }
+
+    info.sync_fence_info = fences.as_mut_ptr().expose_provenance() as u64;
+    sync_file_info_ioctl(fd, &mut info)?;
+    info.sync_fence_info = 0;
+
+    Ok((info, fences))
+}
+
+/// Implements an `ioctl` that passes a pointer to its argument, and returns a new file descriptor
+struct FdCreator<O> {
+    ptr: *mut c_void,
+    _opcode: PhantomData<O>,
+}
+
+impl<O: CompileTimeOpcode> FdCreator<O> {
+    /// # Safety
+    ///
+    /// `O` must provide a valid opcode, that returns a file descriptor on success, and `ptr` must
+    /// point to an argument valid for that opcode.
+    unsafe fn new(ptr: *mut c_void) -> Self {
+        Self {
+            ptr,
+            _opcode: PhantomData,
+        }
+    }
+}
+
+// SAFETY: The caller of FdCreator::new guarantees that the opcode and its argument match, and that
+// the ioctl returns a file descriptor. The argument isn't modified by the kernel.
+unsafe impl<O: CompileTimeOpcode> Ioctl for FdCreator<O> {
+    type Output = OwnedFd;
+
+    const IS_MUTATING: bool = false;
+    const OPCODE: Opcode = O::OPCODE;
+
+    fn as_ptr(&mut self) -> *mut c_void {
+        self.ptr
+    }
+
+    unsafe fn output_from_ptr(
+        out: IoctlOutput,
+        _extract_output: *mut c_void,
+    ) -> rustix::io::Result<Self::Output> {
+        // SAFETY: The ioctl succeeded, so the kernel has just given us that file descriptor. It's
+        // valid and we are its exclusive owner.
+        Ok(unsafe { OwnedFd::from_raw_fd(out) })
+    }
+}
+
+#[derive(Default)]
+#[repr(C)]
+struct udmabuf_create {
+    memfd: u32,
+    flags: u32,
+    offset: u64,
+    size: u64,
+}
+
+// Only used to compute the ioctl opcode, the list is built by udmabuf_create_list()
+#[allow(dead_code)]
+#[repr(C)]
+struct udmabuf_create_list {
+    flags: u32,
+    count: u32,
+}
+
+/// Packs two 32-bit values into a 64-bit one, with the same layout than a struct with two `u32`
+fn pack_u32s(first: u32, second: u32) -> u64 {
+    let mut bytes = [0; 8];
+    bytes[..4].copy_from_slice(&first.to_ne_bytes());
+    bytes[4..].copy_from_slice(&second.to_ne_bytes());
+
+    u64::from_ne_bytes(bytes)
+}
+
+fn udmabuf_flags(cloexec: bool) -> u32 {
+    if cloexec {
+        UDMABUF_FLAGS_CLOEXEC
+    } else {
+        0
+    }
+}
+
+pub(crate) fn udmabuf_create(
+    fd: BorrowedFd<'_>,
+    memfd: BorrowedFd<'_>,
+    offset: u64,
+    size: u64,
+    cloexec: bool,
+) -> Result<OwnedFd> {
+    type Opcode = WriteOpcode<UDMABUF_BASE, UDMABUF_CREATE, udmabuf_create>;
+
+    let mut data = udmabuf_create {
+        memfd: memfd.as_raw_fd().cast_unsigned(),
+        flags: udmabuf_flags(cloexec),
+        offset,
+        size,
+    };
+
+    // SAFETY: This function is unsafe because the opcode has to be valid, and the argument must
+    // match. We have checked those, and data outlives the ioctl call.
+    let ioctl_type =
+        unsafe { FdCreator::<Opcode>::new(core::ptr::from_mut(&mut data).cast::<c_void>()) };
+
+    // SAFETY: This function is unsafe because the driver isn't guaranteed to implement the ioctl,
+    // and to implement it properly. We don't have much of a choice and still have to trust the
+    // kernel there.
+    unsafe { ioctl(fd, ioctl_type) }
+        .map_err(|err| io::Error::from_raw_os_error(err.raw_os_error()).into())
+}
+
+pub(crate) fn udmabuf_create_list(
+    fd: BorrowedFd<'_>,
+    items: &[(BorrowedFd<'_>, u64, u64)],
+    cloexec: bool,
+) -> Result<OwnedFd> {
+    type Opcode = WriteOpcode<UDMABUF_BASE, UDMABUF_CREATE_LIST, udmabuf_create_list>;
+
+    let count =
+        u32::try_from(items.len()).map_err(|_err| io::Error::from(io::ErrorKind::InvalidInput))?;
+
+    // The list is a header followed by a flexible array of udmabuf_create_item. We build it out
+    // of 64-bit words to get the layout and alignment right.
+    let mut data = Vec::with_capacity(1 + items.len() * 3);
+    data.push(pack_u32s(udmabuf_flags(cloexec), count));
+
+    for (memfd, offset, size) in items {
+        data.push(pack_u32s(memfd.as_raw_fd().cast_unsigned(), 0));
+        data.push(*offset);
+        data.push(*size);
+    }
+
+    // SAFETY: This function is unsafe because the opcode has to be valid, and the argument must
+    // match. We have checked those, and data outlives the ioctl call.
+    let ioctl_type = unsafe { FdCreator::<Opcode>::new(data.as_mut_ptr().cast::<c_void>()) };
+
+    // SAFETY: This function is unsafe because the driver isn't guaranteed to implement the ioctl,
+    // and to implement it properly. We don't have much of a choice and still have to trust the
+    // kernel there.
+    unsafe { ioctl(fd, ioctl_type) }
+        .map_err(|err| io::Error::from_raw_os_error(err.raw_os_error()).into())
+}
@@ -22,42 +22,325 @@
 )]
 #![doc = include_str!("../README.md")]
 
+extern crate alloc;
+
+// Only the tests of the tokio feature use it
+#[cfg(all(test, not(feature = "tokio")))]
+use tokio as _;
+
+#[cfg(feature = "mock")]
+use std::sync::OnceLock;
 use std::{
     fs::File,
-    os::{fd::AsFd, unix::io::OwnedFd},
-    path::PathBuf,
+    io,
+    os::fd::{AsFd, BorrowedFd, OwnedFd},
+    path::{Path, PathBuf},
 };
 
+mod allocator;
+pub use allocator::DmaBufAllocator;
+
+mod bench;
+pub use bench::{Benchmark, BenchmarkResult, Percentiles};
+
+#[cfg(feature = "broker")]
+mod broker;
+#[cfg(feature = "broker")]
+pub use broker::{Broker, BrokerClient, BrokerPolicy, ClientPolicy};
+
+mod buffer;
+use buffer::dma_buf_size;
+pub use buffer::DmaBuf;
+
+mod chain;
+pub use chain::HeapChain;
+
+mod cma;
+pub use cma::{CmaArea, CmaStatus};
+
+mod fdinfo;
+
+mod image;
+pub use image::{Image, ImageOptions, ImagePlane, PixelFormat};
+
+mod info;
+pub use info::DmaBufInfo;
+
 mod ioctl;
 use ioctl::dma_heap_alloc;
 
+mod list;
+pub use list::HeapInfo;
+
+mod options;
+pub use options::{AllocOptions, BufferAccess};
+
+#[cfg(feature = "mock")]
+mod mock;
+#[cfg(feature = "mock")]
+pub use mock::{MockFailure, MockHeap};
+
+mod process;
+pub use process::{DmaBufAttribution, ProcessBuffer, ProcessUsage};
+
+mod stats;
+pub use stats::{BufferStats, DmaBufStats, ExporterStats};
+
+mod sync_file;
+pub use sync_file::{FenceInfo, FenceStatus, SyncAccess, SyncFile, SyncFileInfo};
+
+mod transfer;
+pub use transfer::{recv_buffers, send_buffers, BufferMessage};
+
+mod udmabuf;
+pub use udmabuf::{Udmabuf, UdmabufRegion};
+
+mod mmap;
+pub use mmap::{DmaBufMapping, DmaBufReadGuard, DmaBufWriteGuard};
+
+mod pool;
+pub use pool::BufferPool;
+
 use log::debug;
+use rustix::io::Errno;
 use strum_macros::Display;
 
+/// Path to the CMA Heap device
+const CMA_HEAP_PATH: &str = "/dev/dma_heap/linux,cma";
+
+/// Path to the System Heap device
+const SYSTEM_HEAP_PATH: &str = "/dev/dma_heap/system";
+
 /// Error Type for dma-heap
+///
+/// Whenever it's known, the error carries the path of the DMA Heap involved and the errno it
+/// returned. Each error also comes with a hint on how to fix it, available through
+/// [`HeapError::hint`] and as part of its message.
 #[non_exhaustive]
 #[derive(thiserror::Error, Debug)]
 pub enum HeapError {
     /// The requested DMA Heap doesn't exist
-    #[error("The Requested DMA Heap Type ({0}) doesn't exist: {1}")]
-    Missing(HeapKind, PathBuf),
+    #[error(
+        "The Requested DMA Heap Type ({kind}) doesn't exist: {} ({hint})",
+        path.display(),
+        hint = self.hint()
+    )]
+    Missing {
+        /// The requested Heap Type
+        kind: HeapKind,
+
+        /// The path of the Heap device
+        path: PathBuf,
+
+        /// The errno returned by the kernel
+        errno: i32,
+    },
+
+    /// The access to the DMA Heap has been denied
+    #[error(
+        "Permission denied to access the DMA Heap {} ({hint})",
+        path.display(),
+        hint = self.hint()
+    )]
+    PermissionDenied {
+        /// The path of the Heap device
+        path: PathBuf,
+
+        /// The errno returned by the kernel
+        errno: i32,
+    },
+
+    /// The file isn't a DMA Heap
+    #[error("{} isn't a DMA Heap ({hint})", path.display(), hint = self.hint())]
+    NotADmaHeap {
+        /// The path of the file
+        path: PathBuf,
+
+        /// The errno returned by the kernel
+        errno: i32,
+    },
+
+    /// The allocation has been interrupted by a signal
+    #[error(
+        "The allocation on {} has been interrupted ({hint})",
+        path.display(),
+        hint = self.hint()
+    )]
+    Interrupted {
+        /// The path of the Heap device
+        path: PathBuf,
+
+        /// The errno returned by the kernel
+        errno: i32,
+    },
 
     /// An Error occured while accessing the DMA Heap
-    #[error("An Error occurred while accessing the DMA Heap")]
-    Access(std::io::Error),
+    #[error("An Error occurred while accessing the DMA Heap ({hint})", hint = self.hint())]
+    Access {
+        /// The path of the Heap device, if the error is related to a Heap
+        path: Option<PathBuf>,
+
+        /// The underlying error
+        source: io::Error,
+    },
 
     /// The allocation is invalid
-    #[error("The requested allocation is invalid: {0} bytes")]
-    InvalidAllocation(usize),
+    #[error(
+        "The requested allocation on {} is invalid: {len} bytes ({hint})",
+        path.display(),
+        hint = self.hint()
+    )]
+    InvalidAllocation {
+        /// The path of the Heap device
+        path: PathBuf,
+
+        /// The requested size, in bytes
+        len: usize,
+
+        /// The errno returned, or that would have been returned, by the kernel
+        errno: i32,
+    },
+
+    /// The Heap flags have been rejected by the Heap
+    #[error(
+        "The Heap flags are invalid for {}: {flags:#x} ({hint})",
+        path.display(),
+        hint = self.hint()
+    )]
+    InvalidHeapFlags {
+        /// The path of the Heap device
+        path: PathBuf,
+
+        /// The requested Heap flags
+        flags: u64,
+
+        /// The errno returned, or that would have been returned, by the kernel
+        errno: i32,
+    },
+
+    /// The buffer name is invalid
+    #[error("The buffer name is invalid: {0:?} ({hint})", hint = self.hint())]
+    InvalidName(String),
+
+    /// The image layout is invalid
+    #[error(
+        "The image layout is invalid: {format} {width}x{height} ({hint})",
+        hint = self.hint()
+    )]
+    InvalidImage {
+        /// The pixel format of the image
+        format: PixelFormat,
+
+        /// The width of the image, in pixels
+        width: u32,
+
+        /// The height of the image, in pixels
+        height: u32,
+    },
+
+    /// The file descriptor doesn't point to a DMA-Buf
+    #[error("The file descriptor isn't a DMA-Buf ({hint})", hint = self.hint())]
+    NotADmaBuf,
 
     /// There is no memory left to allocate from the DMA Heap
-    #[error("No Memory Left in the Heap")]
-    NoMemoryLeft,
+    #[error("No Memory Left in the Heap {} ({hint})", path.display(), hint = self.hint())]
+    NoMemoryLeft {
+        /// The path of the Heap device
+        path: PathBuf,
+
+        /// The errno returned by the kernel
+        errno: i32,
+
+        /// A snapshot of the CMA state at the time of the failure, for the CMA Heap
+        cma: Option<Box<CmaStatus>>,
+    },
 }
 
-impl From<std::io::Error> for HeapError {
-    fn from(err: std::io::Error) -> Self {
-        Self::Access(err)
+impl HeapError {
+    /// Returns the path of the DMA Heap involved, if any
+    #[must_use]
+    pub fn path(&self) -> Option<&Path> {
+        match self {
+            Self::Missing { path, .. }
+            | Self::PermissionDenied { path, .. }
+            | Self::NotADmaHeap { path, .. }
+            | Self::Interrupted { path, .. }
+            | Self::InvalidAllocation { path, .. }
+            | Self::InvalidHeapFlags { path, .. }
+            | Self::NoMemoryLeft { path, .. } => Some(path),
+            Self::Access { path, .. } => path.as_deref(),
+            Self::InvalidName(_) | Self::InvalidImage { .. } | Self::NotADmaBuf => None,
+        }
+    }
+
+    /// Returns the raw errno of the error, if any
+    #[must_use]
+    pub fn errno(&self) -> Option<i32> {
+        match self {
+            Self::Missing { errno, .. }
+            | Self::PermissionDenied { errno, .. }
+            | Self::NotADmaHeap { errno, .. }
+            | Self::Interrupted { errno, .. }
+            | Self::InvalidAllocation { errno, .. }
+            | Self::InvalidHeapFlags { errno, .. }
+            | Self::NoMemoryLeft { errno, .. } => Some(*errno),
+            Self::Access { source, .. } => source.raw_os_error(),
+            Self::InvalidName(_) | Self::InvalidImage { .. } | Self::NotADmaBuf => None,
+        }
+    }
+
+    /// Returns a hint on how to fix the error
+    #[must_use]
+    pub fn hint(&self) -> &'static str {
+        match self {
+            Self::Missing {
+                kind: HeapKind::Cma,
+                ..
+            } => {
+                "check that the kernel is built with CONFIG_DMABUF_HEAPS_CMA and that a CMA area \
+                 is reserved, for example with the cma= kernel parameter"
+            }
+            Self::Missing {
+                kind: HeapKind::System,
+                ..
+            } => "check that the kernel is built with CONFIG_DMABUF_HEAPS_SYSTEM",
+            Self::Missing {
+                kind: HeapKind::Custom(_),
+                ..
+            } => "check that the driver providing the device is loaded",
+            Self::PermissionDenied { .. } => {
+                "add the user to the group owning the device, usually video, or adjust the udev \
+                 rules"
+            }
+            Self::NotADmaHeap { .. } => "make sure the path points to a device in /dev/dma_heap",
+            Self::Interrupted { .. } => "a signal interrupted the allocation, try again",
+            Self::Access { .. } => "see the underlying I/O error",
+            Self::InvalidAllocation { .. } => {
+                "the size must not be zero, and must fit in memory once aligned to the page size"
+            }
+            Self::InvalidHeapFlags { .. } => {
+                "check the flags supported by the Heap, the CMA and System Heaps don't support any"
+            }
+            Self::InvalidName(_) => "the name must be shorter than 32 bytes and not contain NUL",
+            Self::InvalidImage { .. } => {
+                "the size and alignments must not be zero, and the image must fit in memory"
+            }
+            Self::NotADmaBuf => "the file descriptor must come from a DMA-Buf exporter",
+            Self::NoMemoryLeft { path, .. } if path.as_os_str() == CMA_HEAP_PATH => {
+                "check the size of the CMA area, set by the cma= kernel parameter or the \
+                 reserved-memory node of the Device Tree"
+            }
+            Self::NoMemoryLeft { .. } => "free some memory or allocate a smaller buffer",
+        }
+    }
+}
+
+impl From<io::Error> for HeapError {
+    fn from(err: io::Error) -> Self {
+        Self::Access {
+            path: None,
+            source: err,
+        }
     }
 }
 
@@ -65,7 +348,7 @@ impl From<std::io::Error> for HeapError {
 pub type Result<T> = core::result::Result<T, HeapError>;
 
 /// Various Types of DMA-Buf Heap
-#[derive(Clone, Debug, Display)]
+#[derive(Clone, Debug, Display, PartialEq, Eq)]
 pub enum HeapKind {
     /// A Heap backed by the Contiguous Memory Allocator in the Linux kernel, returning physically
     /// contiguous, cached, buffers
@@ -79,57 +362,295 @@ pub enum HeapKind {
     Custom(PathBuf),
 }
 
+impl HeapKind {
+    /// Returns the path of the device of the Heap
+    pub(crate) fn path(&self) -> PathBuf {
+        match self {
+            Self::Cma => PathBuf::from(CMA_HEAP_PATH),
+            Self::System => PathBuf::from(SYSTEM_HEAP_PATH),
+            Self::Custom(p) => p.clone(),
+        }
+    }
+}
+
+/// Converts an error returned while opening a Heap device into a [`HeapError`]
+pub(crate) fn open_error(err: &io::Error, kind: &HeapKind, path: &Path) -> HeapError {
+    let errno = Errno::from_io_error(err).unwrap_or(Errno::IO);
+
+    match errno {
+        Errno::NOENT | Errno::NODEV | Errno::NXIO | Errno::NOTDIR => HeapError::Missing {
+            kind: kind.clone(),
+            path: path.to_path_buf(),
+            errno: errno.raw_os_error(),
+        },
+        Errno::ACCESS | Errno::PERM => HeapError::PermissionDenied {
+            path: path.to_path_buf(),
+            errno: errno.raw_os_error(),
+        },
+        _ => HeapError::Access {
+            path: Some(path.to_path_buf()),
+            source: io::Error::from_raw_os_error(errno.raw_os_error()),
+        },
+    }
+}
+
+#[derive(Debug)]
+enum Backend {
+    Device(File),
+    #[cfg(feature = "mock")]
+    Mock(MockHeap, OnceLock<OwnedFd>),
+}
+
 /// Our DMA-Buf Heap
 #[derive(Debug)]
 pub struct Heap {
-    file: File,
+    backend: Backend,
     name: HeapKind,
+    path: PathBuf,
 }
 
+// The DmaBufAllocator implementation forwards to the inherent methods, so that they can be
+// used without importing the trait.
+#[allow(clippy::same_name_method)]
 impl Heap {
     /// Opens A DMA-Buf Heap of the specified type
     ///
     /// # Errors
     ///
-    /// Will return [Error] if the Heap Type is not found in the system, or if the open call fails.
+    /// Will return [`HeapError::Missing`] if the Heap Type is not found in the system,
+    /// [`HeapError::PermissionDenied`] if we're not allowed to open it,
+    /// [`HeapError::NotADmaHeap`] if the path doesn't point to a DMA-Buf Heap device, or [Error]
+    /// if the open call, or the sysfs lookup, fails.
     pub fn new(name: HeapKind) -> Result<Self> {
-        let path = match &name {
-            HeapKind::Cma => PathBuf::from("/dev/dma_heap/linux,cma"),
-            HeapKind::System => PathBuf::from("/dev/dma_heap/system"),
-            HeapKind::Custom(p) => p.clone(),
-        };
+        let path = name.path();
 
-        debug!("Using the {} DMA-Buf Heap, at {:#?}", name, path);
+        debug!("Using the {name} DMA-Buf Heap, at {}", path.display());
 
-        #[cfg_attr(feature = "nightly", allow(non_exhaustive_omitted_patterns))]
-        #[allow(clippy::wildcard_enum_match_arm)]
-        let file = File::open(&path).map_err(|err| match err.kind() {
-            std::io::ErrorKind::NotFound => HeapError::Missing(name.clone(), path),
-            _ => HeapError::from(err),
+        let file = File::open(&path).map_err(|err| open_error(&err, &name, &path))?;
+
+        let is_heap = list::is_dma_heap(file.as_fd()).map_err(|err| HeapError::Access {
+            path: Some(path.clone()),
+            source: err,
         })?;
 
+        if !is_heap {
+            return Err(HeapError::NotADmaHeap {
+                path,
+                errno: Errno::NOTTY.raw_os_error(),
+            });
+        }
+
         debug!("Heap found!");
 
-        Ok(Self { file, name })
+        Ok(Self {
+            backend: Backend::Device(file),
+            name,
+            path,
+        })
+    }
+
+    /// Creates a Heap from a file descriptor to a DMA-Buf Heap device
+    ///
+    /// This allows to use a Heap opened by another, more privileged, process. The file descriptor
+    /// is checked against the `dma_heap` class through `/sys/dev/char`, or against the DMA-Buf Heap
+    /// ioctl if sysfs isn't available, and `kind` is only used to report the Heap type and path.
+    ///
+    /// # Errors
+    ///
+    /// Will return [`HeapError::NotADmaHeap`] if the file descriptor doesn't point to a DMA-Buf
+    /// Heap device, or [Error] if it, or sysfs, can't be queried.
+    pub fn from_fd(fd: OwnedFd, kind: HeapKind) -> Result<Self> {
+        let path = kind.path();
+
+        let is_heap = list::is_dma_heap(fd.as_fd()).map_err(|err| HeapError::Access {
+            path: Some(path.clone()),
+            source: err,
+        })?;
+
+        if !is_heap {
+            return Err(HeapError::NotADmaHeap {
+                path,
+                errno: Errno::NOTTY.raw_os_error(),
+            });
+        }
+
+        debug!("Using the {kind} DMA-Buf Heap, from {fd:?}");
+
+        Ok(Self {
+            backend: Backend::Device(File::from(fd)),
+            name: kind,
+            path,
+        })
+    }
+
+    /// Creates a Heap allocating its buffers from a [`MockHeap`]
+    ///
+    /// The file descriptor standing in for the Heap device, returned by [`AsFd`], is only created
+    /// when first needed.
+    #[cfg(feature = "mock")]
+    #[must_use]
+    pub fn mock(mock: MockHeap) -> Self {
+        debug!("Using a mock {} DMA-Buf Heap", mock.kind());
+
+        Self {
+            name: mock.kind().clone(),
+            path: mock.kind().path(),
+            backend: Backend::Mock(mock, OnceLock::new()),
+        }
+    }
+
+    /// Returns the kind of the Heap
+    #[must_use]
+    pub fn kind(&self) -> &HeapKind {
+        &self.name
+    }
+
+    /// Lists the DMA-Buf Heaps available on the system
+    ///
+    /// The Heaps are found by scanning `/dev/dma_heap` and `/sys/class/dma_heap`. If neither
+    /// exists, an empty list is returned.
+    ///
+    /// # Errors
+    ///
+    /// Will return [Error] if the directories or their entries can't be read.
+    pub fn list() -> Result<Vec<HeapInfo>> {
+        list::heaps()
+    }
+
+    /// Attaches a snapshot of the CMA state to the out of memory errors of the CMA Heap
+    fn attach_cma_status(&self, mut err: HeapError) -> HeapError {
+        if self.name != HeapKind::Cma {
+            return err;
+        }
+
+        if let HeapError::NoMemoryLeft {
+            cma: cma @ None, ..
+        } = &mut err
+        {
+            *cma = CmaStatus::read()
+                .inspect_err(|err| debug!("Couldn't read the CMA status: {err}"))
+                .ok()
+                .map(Box::new);
+        }
+
+        err
     }
 
     /// Allocates a DMA-Buf from the Heap with the specified size
     ///
+    /// The size of the returned [`DmaBuf`] is the one reported by the kernel, and might thus be
+    /// larger than `len`.
+    ///
     /// # Panics
     ///
     /// If the errno returned by the underlying `ioctl()` cannot be decoded
-    /// into an `std::io::Error`.
+    /// into an `io::Error`.
     ///
     /// # Errors
     ///
     /// Will return [Error] if the underlying ioctl fails.
-    pub fn allocate(&self, len: usize) -> Result<OwnedFd> {
-        debug!("Allocating Buffer of size {} on {} Heap", len, self.name);
+    pub fn allocate(&self, len: usize) -> Result<DmaBuf> {
+        self.allocate_with(len, &AllocOptions::default())
+    }
 
-        let fd = dma_heap_alloc(self.file.as_fd(), len)?;
+    /// Allocates a DMA-Buf from the Heap with the specified size and options
+    ///
+    /// # Panics
+    ///
+    /// If the errno returned by the underlying `ioctl()` cannot be decoded
+    /// into an `io::Error`.
+    ///
+    /// # Errors
+    ///
+    /// Will return [Error] if the options are invalid for that size, or if the underlying ioctl
+    /// fails.
+    pub fn allocate_with(&self, len: usize, options: &AllocOptions) -> Result<DmaBuf> {
+        debug!(
+            "Allocating Buffer of size {} on {} Heap with {:?}",
+            len, self.name, options
+        );
+
+        options.validate(&self.name, len)?;
+
+        let buffer = match &self.backend {
+            Backend::Device(file) => {
+                let fd = dma_heap_alloc(
+                    file.as_fd(),
+                    &self.path,
+                    len,
+                    options.fd_flags(),
+                    options.raw_heap_flags(),
+                )
+                .map_err(|err| self.attach_cma_status(err))?;
+
+                let size = dma_buf_size(fd.as_fd()).unwrap_or(len);
+                let buffer = DmaBuf::new(fd, size, Some(self.name.clone()));
+
+                if let Some(name) = options.buffer_name() {
+                    buffer.set_name(name)?;
+                }
+
+                buffer
+            }
+            #[cfg(feature = "mock")]
+            Backend::Mock(mock, _) => {
+                let fd = mock.allocate(len, options)?;
+                let size = dma_buf_size(fd.as_fd()).unwrap_or(len);
+
+                DmaBuf::new(fd, size, Some(self.name.clone())).mocked()
+            }
+        };
+
+        debug!("Allocation succeeded, Buffer {buffer:?}");
+
+        Ok(buffer)
+    }
+}
+
+impl AsFd for Heap {
+    fn as_fd(&self) -> BorrowedFd<'_> {
+        match &self.backend {
+            Backend::Device(file) => file.as_fd(),
+            #[cfg(feature = "mock")]
+            Backend::Mock(mock, device) => device.get_or_init(|| mock.device()).as_fd(),
+        }
+    }
+}
+
+impl DmaBufAllocator for Heap {
+    fn allocate_with(&self, len: usize, options: &AllocOptions) -> Result<DmaBuf> {
+        self.allocate_with(len, options)
+    }
+
+    fn allocate(&self, len: usize) -> Result<DmaBuf> {
+        self.allocate(len)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{fs::File, os::fd::OwnedFd, path::PathBuf};
+
+    use crate::{Heap, HeapError, HeapKind};
+
+    #[test]
+    fn open_character_device() {
+        let err = Heap::new(HeapKind::Custom(PathBuf::from("/dev/null"))).unwrap_err();
+
+        assert!(
+            matches!(err, HeapError::NotADmaHeap { .. }),
+            "/dev/null was accepted as a DMA-Buf Heap"
+        );
+    }
 
-        debug!("Allocation succeeded, Buffer File Descriptor {:#?}", fd);
+    #[test]
+    fn character_device_from_fd() {
+        let fd = OwnedFd::from(File::open("/dev/null").unwrap());
+        let err = Heap::from_fd(fd, HeapKind::System).unwrap_err();
 
-        Ok(fd)
+        assert!(
+            matches!(err, HeapError::NotADmaHeap { .. }),
+            "/dev/null was accepted as a DMA-Buf Heap"
+        );
     }
 }
@@ -0,0 +1,275 @@
+use alloc::collections::BTreeMap;
+use std::{
+    fs, io,
+    os::{
+        fd::BorrowedFd,
+        unix::fs::{FileTypeExt, MetadataExt},
+    },
+    path::{Path, PathBuf},
+};
+
+use log::debug;
+use rustix::fs::{fstat, major, minor, FileType};
+
+use crate::{ioctl::dma_heap_probe, HeapKind, Result};
+
+const DEV_DMA_HEAP_DIR: &str = "/dev/dma_heap";
+const SYS_DMA_HEAP_DIR: &str = "/sys/class/dma_heap";
+const SYS_DEV_CHAR_DIR: &str = "/sys/dev/char";
+
+/// Description of a DMA-Buf Heap exposed by the kernel
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct HeapInfo {
+    name: String,
+    path: PathBuf,
+    major: u32,
+    minor: u32,
+}
+
+impl HeapInfo {
+    /// Returns the name of the Heap, as reported by the kernel
+    #[must_use]
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// Returns the Path to the Heap device node
+    #[must_use]
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Returns the major number of the Heap device node
+    #[must_use]
+    pub fn major(&self) -> u32 {
+        self.major
+    }
+
+    /// Returns the minor number of the Heap device node
+    #[must_use]
+    pub fn minor(&self) -> u32 {
+        self.minor
+    }
+
+    /// Returns the [`HeapKind`] to use with [`crate::Heap::new`] to open this Heap
+    #[must_use]
+    pub fn kind(&self) -> HeapKind {
+        match self.name.as_str() {
+            "linux,cma" => HeapKind::Cma,
+            "system" => HeapKind::System,
+            _ => HeapKind::Custom(self.path.clone()),
+        }
+    }
+}
+
+fn read_dir_if_exists(path: &Path) -> io::Result<Option<fs::ReadDir>> {
+    match fs::read_dir(path) {
+        Ok(dir) => Ok(Some(dir)),
+        Err(err) if err.kind() == io::ErrorKind::NotFound => Ok(None),
+        Err(err) => Err(err),
+    }
+}
+
+fn parse_dev_numbers(content: &str) -> Option<(u32, u32)> {
+    let (major, minor) = content.trim().split_once(':')?;
+
+    Some((major.parse().ok()?, minor.parse().ok()?))
+}
+
+/// Checks whether a character device belongs to the `dma_heap` class
+///
+/// Returns `None` if sysfs doesn't know about the device, ie. because it isn't mounted in a
+/// container.
+fn is_dma_heap_device(major: u32, minor: u32) -> io::Result<Option<bool>> {
+    let subsystem = Path::new(SYS_DEV_CHAR_DIR)
+        .join(format!("{major}:{minor}"))
+        .join("subsystem");
+
+    match fs::read_link(&subsystem) {
+        Ok(target) => Ok(Some(
+            target.file_name().is_some_and(|name| name == "dma_heap"),
+        )),
+        Err(err) if err.kind() == io::ErrorKind::NotFound => Ok(None),
+        Err(err) => Err(err),
+    }
+}
+
+/// Checks whether a file descriptor points to a DMA-Buf Heap device
+///
+/// The device class is looked up in sysfs and, if it isn't available, we fall back to checking
+/// whether the device implements the DMA-Buf Heap ioctl.
+pub(crate) fn is_dma_heap(fd: BorrowedFd<'_>) -> io::Result<bool> {
+    let stat = fstat(fd)?;
+
+    if FileType::from_raw_mode(stat.st_mode) != FileType::CharacterDevice {
+        return Ok(false);
+    }
+
+    let (major, minor) = (major(stat.st_rdev), minor(stat.st_rdev));
+    if let Some(is_heap) = is_dma_heap_device(major, minor)? {
+        return Ok(is_heap);
+    }
+
+    debug!("Device {major}:{minor} not found in sysfs, probing it");
+
+    Ok(dma_heap_probe(fd))
+}
+
+pub(crate) fn heaps() -> Result<Vec<HeapInfo>> {
+    heaps_in(Path::new(DEV_DMA_HEAP_DIR), Path::new(SYS_DMA_HEAP_DIR))
+}
+
+/// Lists the Heaps with a device node in `dev_dir`, or a class entry in `sys_dir`
+///
+/// The entries that can't be read are skipped, so that a single broken entry doesn't hide all
+/// the others.
+fn heaps_in(dev_dir: &Path, sys_dir: &Path) -> Result<Vec<HeapInfo>> {
+    let mut heaps = BTreeMap::new();
+
+    if let Some(dir) = read_dir_if_exists(dev_dir)? {
+        for entry in dir {
+            let entry = entry?;
+            let path = entry.path();
+            let metadata = match fs::metadata(&path) {
+                Ok(metadata) => metadata,
+                Err(err) => {
+                    debug!("Ignoring {}: {err}", path.display());
+                    continue;
+                }
+            };
+
+            if !metadata.file_type().is_char_device() {
+                debug!("Ignoring {}: not a character device", path.display());
+                continue;
+            }
+
+            let name = entry.file_name().to_string_lossy().into_owned();
+            let rdev = metadata.rdev();
+
+            heaps.insert(
+                name.clone(),
+                HeapInfo {
+                    name,
+                    path,
+                    major: major(rdev),
+                    minor: minor(rdev),
+                },
+            );
+        }
+    }
+
+    // The device nodes might not be there (in a container for example), but sysfs will still
+    // tell us what the kernel provides.
+    if let Some(dir) = read_dir_if_exists(sys_dir)? {
+        for entry in dir {
+            let entry = entry?;
+            let name = entry.file_name().to_string_lossy().into_owned();
+
+            if heaps.contains_key(&name) {
+                continue;
+            }
+
+            let content = match fs::read_to_string(entry.path().join("dev")) {
+                Ok(content) => content,
+                Err(err) => {
+                    debug!("Ignoring {name}: couldn't read device numbers: {err}");
+                    continue;
+                }
+            };
+
+            let Some((major, minor)) = parse_dev_numbers(&content) else {
+                debug!("Ignoring {name}: couldn't parse device numbers {content:?}");
+                continue;
+            };
+
+            heaps.insert(
+                name.clone(),
+                HeapInfo {
+                    path: dev_dir.join(&name),
+                    name,
+                    major,
+                    minor,
+                },
+            );
+        }
+    }
+
+    Ok(heaps.into_values().collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        env,
+        fs::{self, File},
+        os::{fd::AsFd, unix::fs::symlink},
+        path::Path,
+        process,
+    };
+
+    use super::{heaps_in, HeapInfo};
+    use crate::ioctl::dma_heap_probe;
+
+    #[test]
+    fn merge_dev_and_sysfs() {
+        let root = env::temp_dir().join(format!("dma-heap-list-{}", process::id()));
+        let dev = root.join("dev");
+        let sys = root.join("sys");
+
+        fs::create_dir_all(&dev).unwrap();
+        symlink("/dev/null", dev.join("system")).unwrap();
+        symlink(root.join("missing"), dev.join("broken")).unwrap();
+        fs::write(dev.join("file"), "").unwrap();
+
+        for (name, numbers) in [
+            ("system", Some("250:0\n")),
+            ("linux,cma", Some("250:1\n")),
+            ("garbage", Some("not a device\n")),
+            ("empty", None),
+        ] {
+            fs::create_dir_all(sys.join(name)).unwrap();
+            if let Some(numbers) = numbers {
+                fs::write(sys.join(name).join("dev"), numbers).unwrap();
+            }
+        }
+
+        let heaps = heaps_in(&dev, &sys);
+        fs::remove_dir_all(&root).unwrap();
+
+        assert_eq!(
+            heaps.unwrap(),
+            [
+                HeapInfo {
+                    name: String::from("linux,cma"),
+                    path: dev.join("linux,cma"),
+                    major: 250,
+                    minor: 1,
+                },
+                // The device node takes precedence over sysfs
+                HeapInfo {
+                    name: String::from("system"),
+                    path: dev.join("system"),
+                    major: 1,
+                    minor: 3,
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn missing_directories() {
+        let missing = Path::new("/nonexistent");
+
+        assert_eq!(heaps_in(missing, missing).unwrap(), []);
+    }
+
+    #[test]
+    fn probe_character_device() {
+        let file = File::open("/dev/null").unwrap();
+
+        assert!(
+            !dma_heap_probe(file.as_fd()),
+            "/dev/null implements the DMA-Buf Heap ioctl"
+        );
+    }
+}
@@ -0,0 +1,270 @@
+use core::{
+    ffi::c_void,
+    ops::{Deref, DerefMut},
+    ptr::{self, NonNull},
+    slice,
+};
+use std::{io, os::fd::AsFd};
+
+use log::{debug, warn};
+use rustix::{
+    fs::{fcntl_getfl, OFlags},
+    mm::{mmap, munmap, MapFlags, ProtFlags},
+};
+
+use crate::{
+    ioctl::{
+        dma_buf_sync, DMA_BUF_SYNC_END, DMA_BUF_SYNC_READ, DMA_BUF_SYNC_RW, DMA_BUF_SYNC_START,
+        DMA_BUF_SYNC_WRITE,
+    },
+    DmaBuf, Result,
+};
+
+/// A CPU mapping of a [`DmaBuf`]
+///
+/// The mapping itself doesn't give access to the buffer content. One has to create a guard using
+/// [`DmaBufMapping::read`], [`DmaBufMapping::write`] or [`DmaBufMapping::read_write`] that will
+/// perform the cache maintenance operations required for the CPU to access the buffer, and
+/// release it when dropped.
+///
+/// The buffer is unmapped when the mapping is dropped.
+#[derive(Debug)]
+pub struct DmaBufMapping<'a> {
+    buffer: &'a DmaBuf,
+    ptr: NonNull<u8>,
+    len: usize,
+    writable: bool,
+}
+
+// SAFETY: The mapping only gives shared access to the memory through shared references, and
+// exclusive access through exclusive references, just like a Vec would.
+unsafe impl Send for DmaBufMapping<'_> {}
+
+// SAFETY: See above.
+unsafe impl Sync for DmaBufMapping<'_> {}
+
+impl<'a> DmaBufMapping<'a> {
+    pub(crate) fn new(buffer: &'a DmaBuf) -> Result<Self> {
+        let len = buffer.len();
+
+        // Read-only buffers can't be mapped for writing
+        let access = fcntl_getfl(buffer.as_fd()).map_err(io::Error::from)? & OFlags::RWMODE;
+        let writable = access == OFlags::RDWR;
+
+        let mut prot = ProtFlags::READ;
+        if writable {
+            prot.insert(ProtFlags::WRITE);
+        }
+
+        debug!(
+            "Mapping buffer {:?} of size {len} with {prot:?}",
+            buffer.as_fd()
+        );
+
+        // SAFETY: We let the kernel pick the address, so we can't overlap with any existing
+        // mapping, and the file descriptor is valid for as long as the buffer is.
+        let ptr = unsafe {
+            mmap(
+                ptr::null_mut(),
+                len,
+                prot,
+                MapFlags::SHARED,
+                buffer.as_fd(),
+                0,
+            )
+        }
+        .map_err(io::Error::from)?;
+
+        let ptr = NonNull::new(ptr.cast::<u8>())
+            .ok_or_else(|| io::Error::from(io::ErrorKind::InvalidData))?;
+
+        Ok(Self {
+            buffer,
+            ptr,
+            len,
+            writable,
+        })
+    }
+
+    /// Returns the size of the mapping, in bytes
+    #[must_use]
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    /// Returns true if the mapping has a size of zero
+    #[must_use]
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    fn begin(&self, flags: u32) -> Result<()> {
+        if flags & DMA_BUF_SYNC_WRITE != 0 && !self.writable {
+            return Err(io::Error::from(io::ErrorKind::PermissionDenied).into());
+        }
+
+        if !self.buffer.needs_sync() {
+            return Ok(());
+        }
+
+        dma_buf_sync(self.buffer.as_fd(), DMA_BUF_SYNC_START | flags)
+    }
+
+    fn end(&self, flags: u32) {
+        if !self.buffer.needs_sync() {
+            return;
+        }
+
+        if let Err(err) = dma_buf_sync(self.buffer.as_fd(), DMA_BUF_SYNC_END | flags) {
+            warn!(
+                "Couldn't end CPU access to {:?}: {err}",
+                self.buffer.as_fd()
+            );
+        }
+    }
+
+    /// Starts a read access to the buffer by the CPU
+    ///
+    /// # Errors
+    ///
+    /// Will return [Error] if the `DMA_BUF_IOCTL_SYNC` ioctl fails.
+    pub fn read(&self) -> Result<DmaBufReadGuard<'_, 'a>> {
+        self.begin(DMA_BUF_SYNC_READ)?;
+
+        Ok(DmaBufReadGuard { mapping: self })
+    }
+
+    /// Starts a write access to the buffer by the CPU
+    ///
+    /// The previous content of the buffer isn't guaranteed to be visible through the guard, use
+    /// [`DmaBufMapping::read_write`] if it's needed.
+    ///
+    /// # Errors
+    ///
+    /// Will return [Error] if the buffer is read-only, or if the `DMA_BUF_IOCTL_SYNC` ioctl
+    /// fails.
+    pub fn write(&mut self) -> Result<DmaBufWriteGuard<'_, 'a>> {
+        self.begin(DMA_BUF_SYNC_WRITE)?;
+
+        Ok(DmaBufWriteGuard {
+            mapping: self,
+            flags: DMA_BUF_SYNC_WRITE,
+        })
+    }
+
+    /// Starts a read and write access to the buffer by the CPU
+    ///
+    /// # Errors
+    ///
+    /// Will return [Error] if the buffer is read-only, or if the `DMA_BUF_IOCTL_SYNC` ioctl
+    /// fails.
+    pub fn read_write(&mut self) -> Result<DmaBufWriteGuard<'_, 'a>> {
+        self.begin(DMA_BUF_SYNC_RW)?;
+
+        Ok(DmaBufWriteGuard {
+            mapping: self,
+            flags: DMA_BUF_SYNC_RW,
+        })
+    }
+}
+
+impl Drop for DmaBufMapping<'_> {
+    fn drop(&mut self) {
+        // SAFETY: The pointer and length are the ones returned by mmap, and no guard can outlive
+        // the mapping so nothing can access that memory anymore.
+        if let Err(err) = unsafe { munmap(self.ptr.as_ptr().cast::<c_void>(), self.len) } {
+            warn!("Couldn't unmap buffer {:?}: {err}", self.buffer.as_fd());
+        }
+    }
+}
+
+/// A read access to a [`DmaBufMapping`]
+///
+/// The CPU access is ended when the guard is dropped.
+#[derive(Debug)]
+pub struct DmaBufReadGuard<'m, 'a> {
+    mapping: &'m DmaBufMapping<'a>,
+}
+
+impl Deref for DmaBufReadGuard<'_, '_> {
+    type Target = [u8];
+
+    fn deref(&self) -> &Self::Target {
+        // SAFETY: The pointer is valid for len bytes for as long as the mapping is, and we only
+        // hand out shared references while we hold a shared reference to the mapping.
+        unsafe { slice::from_raw_parts(self.mapping.ptr.as_ptr(), self.mapping.len) }
+    }
+}
+
+impl Drop for DmaBufReadGuard<'_, '_> {
+    fn drop(&mut self) {
+        self.mapping.end(DMA_BUF_SYNC_READ);
+    }
+}
+
+/// A write access to a [`DmaBufMapping`]
+///
+/// The CPU access is ended when the guard is dropped.
+#[derive(Debug)]
+pub struct DmaBufWriteGuard<'m, 'a> {
+    mapping: &'m mut DmaBufMapping<'a>,
+    flags: u32,
+}
+
+impl Deref for DmaBufWriteGuard<'_, '_> {
+    type Target = [u8];
+
+    fn deref(&self) -> &Self::Target {
+        // SAFETY: The pointer is valid for len bytes for as long as the mapping is.
+        unsafe { slice::from_raw_parts(self.mapping.ptr.as_ptr(), self.mapping.len) }
+    }
+}
+
+impl DerefMut for DmaBufWriteGuard<'_, '_> {
+    fn deref_mut(&mut self) -> &mut Self::Target {
+        // SAFETY: The pointer is valid for len bytes for as long as the mapping is, and we hold
+        // the only reference to the mapping.
+        unsafe { slice::from_raw_parts_mut(self.mapping.ptr.as_ptr(), self.mapping.len) }
+    }
+}
+
+impl Drop for DmaBufWriteGuard<'_, '_> {
+    fn drop(&mut self) {
+        self.mapping.end(self.flags);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use rustix::{
+        fs::{ftruncate, memfd_create, MemfdFlags},
+        io::Errno,
+    };
+
+    use crate::DmaBuf;
+
+    #[test]
+    fn foreign_buffers_are_synchronized() {
+        let memfd = memfd_create("dma-heap-test", MemfdFlags::CLOEXEC).unwrap();
+        ftruncate(&memfd, 4096).unwrap();
+
+        // A memfd doesn't implement DMA_BUF_IOCTL_SYNC, and must not be silently accepted
+        let buffer = DmaBuf::from(memfd);
+        let mapping = buffer.mmap().unwrap();
+        let err = mapping.read().unwrap_err();
+        assert_eq!(err.errno(), Some(Errno::NOTTY.raw_os_error()));
+    }
+
+    #[cfg(feature = "mock")]
+    #[test]
+    fn mock_buffers_skip_synchronization() {
+        use crate::{Heap, HeapKind, MockHeap};
+
+        let heap = Heap::mock(MockHeap::new(HeapKind::System));
+        let buffer = heap.allocate(4096).unwrap();
+        let mut mapping = buffer.mmap().unwrap();
+
+        mapping.write().unwrap().fill(0x42);
+        assert!(mapping.read().unwrap().iter().all(|byte| *byte == 0x42));
+    }
+}
@@ -0,0 +1,222 @@
+use alloc::{collections::VecDeque, sync::Arc};
+use std::{
+    fs, io,
+    os::fd::{AsFd, AsRawFd, BorrowedFd, OwnedFd},
+    sync::{Mutex, MutexGuard, PoisonError},
+};
+
+use log::debug;
+use rustix::{
+    fs::{fcntl_add_seals, ftruncate, memfd_create, open, MemfdFlags, Mode, OFlags, SealFlags},
+    io::{fcntl_setfd, Errno, FdFlags},
+    param::page_size,
+};
+
+use crate::{ioctl::dma_heap_alloc_error, AllocOptions, BufferAccess, HeapError, HeapKind, Result};
+
+/// An error to inject in a [`MockHeap`] allocation
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum MockFailure {
+    /// The allocation fails with [`HeapError::InvalidAllocation`]
+    InvalidAllocation,
+
+    /// The allocation fails with [`HeapError::NoMemoryLeft`]
+    NoMemoryLeft,
+
+    /// The allocation fails as if the kernel returned this errno value
+    Errno(i32),
+}
+
+/// Returns the name of a buffer allocated by a [`MockHeap`], ie. the name of its memfd
+pub(crate) fn mock_buffer_name(fd: BorrowedFd<'_>) -> io::Result<Option<String>> {
+    let target = fs::read_link(format!("/proc/self/fd/{}", fd.as_raw_fd()))?;
+    let target = target.to_string_lossy();
+
+    let name = target
+        .strip_prefix("/memfd:")
+        .map(|name| name.strip_suffix(" (deleted)").unwrap_or(name))
+        .filter(|name| !name.is_empty())
+        .map(str::to_owned);
+
+    Ok(name)
+}
+
+#[derive(Debug, Default)]
+struct MockState {
+    failures: VecDeque<MockFailure>,
+    max_allocation: Option<usize>,
+}
+
+/// A fake DMA-Buf Heap backed by memfd
+///
+/// It allows to test code relying on [`crate::Heap`] on systems without any DMA-Buf Heap. The
+/// buffers it allocates are regular memory, and can be mapped and accessed like any DMA-Buf.
+///
+/// A [`MockHeap`] is a handle to a shared state, so a clone can be kept around to inject failures
+/// once the [`crate::Heap`] has been created.
+///
+/// # Example
+///
+/// ```
+/// use dma_heap::{Heap, HeapError, HeapKind, MockFailure, MockHeap};
+///
+/// let mock = MockHeap::new(HeapKind::Cma);
+/// let heap = Heap::mock(mock.clone());
+///
+/// mock.fail_next(MockFailure::NoMemoryLeft);
+/// assert!(matches!(
+///     heap.allocate(4096),
+///     Err(HeapError::NoMemoryLeft { .. })
+/// ));
+/// assert_eq!(heap.allocate(4096).unwrap().len(), 4096);
+/// ```
+#[derive(Clone, Debug)]
+pub struct MockHeap {
+    kind: HeapKind,
+    state: Arc<Mutex<MockState>>,
+}
+
+impl MockHeap {
+    /// Creates a new mock Heap, pretending to be a Heap of the given kind
+    #[must_use]
+    pub fn new(kind: HeapKind) -> Self {
+        Self {
+            kind,
+            state: Arc::new(Mutex::new(MockState::default())),
+        }
+    }
+
+    /// Sets the size above which the allocations will fail with [`HeapError::NoMemoryLeft`]
+    #[must_use]
+    pub fn max_allocation(self, len: usize) -> Self {
+        self.state().max_allocation = Some(len);
+        self
+    }
+
+    fn state(&self) -> MutexGuard<'_, MockState> {
+        self.state.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    /// Makes the next allocation fail with the given error
+    ///
+    /// Failures are queued, and each allocation consumes one of them.
+    pub fn fail_next(&self, failure: MockFailure) {
+        self.state().failures.push_back(failure);
+    }
+
+    pub(crate) fn kind(&self) -> &HeapKind {
+        &self.kind
+    }
+
+    /// Creates a file descriptor standing in for the Heap device
+    ///
+    /// # Panics
+    ///
+    /// If the memfd can't be created, ie. if the process ran out of file descriptors.
+    pub(crate) fn device(&self) -> OwnedFd {
+        let name = format!("dma-heap-mock-{}", self.kind);
+
+        memfd_create(name, MemfdFlags::CLOEXEC).expect("Couldn't create the mock Heap device")
+    }
+
+    pub(crate) fn allocate(&self, len: usize, options: &AllocOptions) -> Result<OwnedFd> {
+        {
+            let mut state = self.state();
+
+            if let Some(failure) = state.failures.pop_front() {
+                debug!("Injecting failure {failure:?}");
+
+                let (errno, heap_flags) = match failure {
+                    MockFailure::InvalidAllocation => (Errno::INVAL, 0),
+                    MockFailure::NoMemoryLeft => (Errno::NOMEM, 0),
+                    MockFailure::Errno(errno) => {
+                        (Errno::from_raw_os_error(errno), options.raw_heap_flags())
+                    }
+                };
+
+                return Err(dma_heap_alloc_error(
+                    errno,
+                    &self.kind.path(),
+                    len,
+                    heap_flags,
+                ));
+            }
+
+            if state.max_allocation.is_some_and(|max| len > max) {
+                return Err(HeapError::NoMemoryLeft {
+                    path: self.kind.path(),
+                    errno: Errno::NOMEM.raw_os_error(),
+                    cma: None,
+                });
+            }
+        }
+
+        let size = len.checked_next_multiple_of(page_size()).ok_or_else(|| {
+            HeapError::InvalidAllocation {
+                path: self.kind.path(),
+                len,
+                errno: Errno::INVAL.raw_os_error(),
+            }
+        })?;
+
+        // memfd don't support DMA_BUF_SET_NAME, so the best we can do is to name the memfd itself.
+        // The unnamed buffers get an empty name, reported as no name at all.
+        let memfd = memfd_create(
+            options.buffer_name().unwrap_or_default(),
+            MemfdFlags::CLOEXEC | MemfdFlags::ALLOW_SEALING,
+        )
+        .map_err(io::Error::from)?;
+
+        ftruncate(&memfd, size as u64).map_err(io::Error::from)?;
+
+        // Just like a DMA-Buf, the buffer size can't change
+        fcntl_add_seals(
+            &memfd,
+            SealFlags::SHRINK | SealFlags::GROW | SealFlags::SEAL,
+        )
+        .map_err(io::Error::from)?;
+
+        let fd = match options.buffer_access() {
+            BufferAccess::ReadWrite => memfd,
+            BufferAccess::ReadOnly => open(
+                format!("/proc/self/fd/{}", memfd.as_raw_fd()),
+                OFlags::RDONLY | OFlags::CLOEXEC,
+                Mode::empty(),
+            )
+            .map_err(io::Error::from)?,
+        };
+
+        if !options.is_cloexec() {
+            fcntl_setfd(fd.as_fd(), FdFlags::empty()).map_err(io::Error::from)?;
+        }
+
+        Ok(fd)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::os::fd::{AsFd, AsRawFd};
+
+    use crate::{AllocOptions, Heap, HeapKind, MockHeap};
+
+    #[test]
+    fn buffer_name() {
+        let heap = Heap::mock(MockHeap::new(HeapKind::System));
+
+        let named = heap
+            .allocate_with(4096, &AllocOptions::new().name("frame"))
+            .unwrap();
+        assert_eq!(named.name().unwrap().as_deref(), Some("frame"));
+
+        let unnamed = heap.allocate(4096).unwrap();
+        assert_eq!(unnamed.name().unwrap(), None);
+    }
+
+    #[test]
+    fn device_is_created_once() {
+        let heap = Heap::mock(MockHeap::new(HeapKind::System));
+
+        assert_eq!(heap.as_fd().as_raw_fd(), heap.as_fd().as_raw_fd());
+    }
+}
@@ -0,0 +1,151 @@
+use rustix::{fs::OFlags, io::Errno, param::page_size};
+
+use crate::{buffer::dma_buf_name, HeapError, HeapKind, Result};
+
+/// Access Mode of the DMA-Buf File Descriptor
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum BufferAccess {
+    /// The buffer can only be read through the file descriptor
+    ReadOnly,
+
+    /// The buffer can be read and written through the file descriptor
+    #[default]
+    ReadWrite,
+}
+
+/// Options to customize a DMA-Buf allocation
+///
+/// # Example
+///
+/// ```no_run
+/// use dma_heap::{AllocOptions, BufferAccess, Heap, HeapKind};
+///
+/// let heap = Heap::new(HeapKind::System).unwrap();
+///
+/// let options = AllocOptions::new()
+///     .access(BufferAccess::ReadOnly)
+///     .cloexec(false);
+///
+/// let buffer = heap.allocate_with(4096, &options).unwrap();
+/// ```
+#[derive(Clone, Debug)]
+pub struct AllocOptions {
+    access: BufferAccess,
+    cloexec: bool,
+    heap_flags: u64,
+    name: Option<String>,
+}
+
+impl Default for AllocOptions {
+    fn default() -> Self {
+        Self {
+            access: BufferAccess::ReadWrite,
+            cloexec: true,
+            heap_flags: 0,
+            name: None,
+        }
+    }
+}
+
+impl AllocOptions {
+    /// Creates a new set of options, allocating a read-write, close-on-exec, buffer.
+    #[must_use]
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Sets the access mode of the buffer file descriptor
+    #[must_use]
+    pub fn access(mut self, access: BufferAccess) -> Self {
+        self.access = access;
+        self
+    }
+
+    /// Sets whether the buffer file descriptor will be closed on `exec()`
+    ///
+    /// The file descriptor is closed on `exec()` by default. Disabling it allows a child process
+    /// to inherit the buffer.
+    #[must_use]
+    pub fn cloexec(mut self, cloexec: bool) -> Self {
+        self.cloexec = cloexec;
+        self
+    }
+
+    /// Sets the Heap-specific flags
+    ///
+    /// The upstream kernel doesn't define any flag and will reject any non-zero value, but some
+    /// vendor heaps do. Non-zero flags are thus only allowed on [`HeapKind::Custom`] Heaps.
+    #[must_use]
+    pub fn heap_flags(mut self, flags: u64) -> Self {
+        self.heap_flags = flags;
+        self
+    }
+
+    /// Sets the debug name of the buffer
+    ///
+    /// See [`crate::DmaBuf::set_name`].
+    #[must_use]
+    pub fn name(mut self, name: &str) -> Self {
+        self.name = Some(name.to_owned());
+        self
+    }
+
+    pub(crate) fn fd_flags(&self) -> OFlags {
+        let mut fd_flags = match self.access {
+            BufferAccess::ReadOnly => OFlags::RDONLY,
+            BufferAccess::ReadWrite => OFlags::RDWR,
+        };
+
+        if self.cloexec {
+            fd_flags.insert(OFlags::CLOEXEC);
+        }
+
+        fd_flags
+    }
+
+    pub(crate) fn buffer_access(&self) -> BufferAccess {
+        self.access
+    }
+
+    pub(crate) fn is_cloexec(&self) -> bool {
+        self.cloexec
+    }
+
+    pub(crate) fn raw_heap_flags(&self) -> u64 {
+        self.heap_flags
+    }
+
+    pub(crate) fn buffer_name(&self) -> Option<&str> {
+        self.name.as_deref()
+    }
+
+    /// Checks that an allocation of `len` bytes on a Heap of type `kind` with these options will
+    /// be accepted by the kernel
+    ///
+    /// The file descriptor flags are valid by construction. The CMA and System Heaps don't define
+    /// any heap flag, but we can only rely on the kernel to check them for the other Heaps.
+    pub(crate) fn validate(&self, kind: &HeapKind, len: usize) -> Result<()> {
+        // The kernel will page-align the length, and reject anything that ends up being 0.
+        if len == 0 || len.checked_next_multiple_of(page_size()).is_none() {
+            return Err(HeapError::InvalidAllocation {
+                path: kind.path(),
+                len,
+                errno: Errno::INVAL.raw_os_error(),
+            });
+        }
+
+        if self.heap_flags != 0 && matches!(kind, HeapKind::Cma | HeapKind::System) {
+            return Err(HeapError::InvalidHeapFlags {
+                path: kind.path(),
+                flags: self.heap_flags,
+                errno: Errno::INVAL.raw_os_error(),
+            });
+        }
+
+        if let Some(name) = &self.name {
+            dma_buf_name(name)?;
+        }
+
+        Ok(())
+    }
+}
@@ -0,0 +1,303 @@
+use alloc::collections::{BTreeMap, BTreeSet};
+use std::{
+    fs,
+    os::unix::fs::MetadataExt,
+    sync::{Mutex, MutexGuard, PoisonError},
+};
+
+use log::debug;
+use rustix::{fs::fstat, param::page_size};
+
+use crate::{AllocOptions, DmaBuf, DmaBufAllocator, Heap, Result};
+
+/// The device and inode of a buffer, which identify it across file descriptors
+type BufferId = (u64, u64);
+
+#[derive(Debug, Default)]
+struct PoolState {
+    free: BTreeMap<usize, Vec<(BufferId, DmaBuf)>>,
+    retained: usize,
+
+    // The buffers allocated by the pool, to recognize them when they are released
+    allocated: BTreeSet<BufferId>,
+
+    // The buffers currently in free, to avoid retaining the same buffer twice
+    retained_ids: BTreeSet<BufferId>,
+}
+
+/// Returns the identifier of a buffer
+fn buffer_id(buffer: &DmaBuf) -> Option<BufferId> {
+    fstat(buffer)
+        .inspect_err(|err| debug!("Couldn't retrieve the inode of {buffer:?}: {err}"))
+        .ok()
+        .map(|stat| (stat.st_dev, stat.st_ino))
+}
+
+/// Returns the identifiers of all the files opened by the process
+fn open_files() -> Option<BTreeSet<BufferId>> {
+    let entries = fs::read_dir("/proc/self/fd")
+        .inspect_err(|err| debug!("Couldn't list the open files: {err}"))
+        .ok()?;
+
+    Some(
+        entries
+            .filter_map(|entry| fs::metadata(entry.ok()?.path()).ok())
+            .map(|metadata| (metadata.dev(), metadata.ino()))
+            .collect(),
+    )
+}
+
+/// A pool of DMA-Bufs allocated from a [`Heap`], or any other [`DmaBufAllocator`]
+///
+/// Buffers given back to the pool with [`BufferPool::release`] are kept around, sorted by size,
+/// and handed back on the next allocation of the same size instead of going through the kernel
+/// again. Only the buffers allocated by the pool itself are retained.
+///
+/// # Example
+///
+/// ```no_run
+/// use dma_heap::{BufferPool, Heap, HeapKind};
+///
+/// let heap = Heap::new(HeapKind::Cma).unwrap();
+/// let pool = BufferPool::new(heap).max_retained_bytes(64 << 20);
+///
+/// pool.prewarm(1 << 20, 8).unwrap();
+///
+/// let buffer = pool.allocate(1 << 20).unwrap();
+/// pool.release(buffer);
+/// ```
+#[derive(Debug)]
+pub struct BufferPool<A = Heap> {
+    allocator: A,
+    options: AllocOptions,
+    max_retained: usize,
+    state: Mutex<PoolState>,
+}
+
+impl<A: DmaBufAllocator> BufferPool<A> {
+    /// Creates a new, empty, pool on top of an allocator
+    ///
+    /// By default, the pool doesn't limit how much memory it retains.
+    #[must_use]
+    pub fn new(allocator: A) -> Self {
+        Self {
+            allocator,
+            options: AllocOptions::default(),
+            max_retained: usize::MAX,
+            state: Mutex::new(PoolState::default()),
+        }
+    }
+
+    /// Sets the options used to allocate the buffers
+    #[must_use]
+    pub fn options(mut self, options: AllocOptions) -> Self {
+        self.options = options;
+        self
+    }
+
+    /// Sets the maximum number of bytes the pool will retain
+    ///
+    /// Buffers released while the pool is full are freed.
+    #[must_use]
+    pub fn max_retained_bytes(mut self, max: usize) -> Self {
+        self.max_retained = max;
+        self
+    }
+
+    fn state(&self) -> MutexGuard<'_, PoolState> {
+        self.state.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    fn size_class(len: usize) -> Option<usize> {
+        len.checked_next_multiple_of(page_size())
+    }
+
+    /// Returns the number of bytes currently retained by the pool
+    #[must_use]
+    pub fn retained_bytes(&self) -> usize {
+        self.state().retained
+    }
+
+    /// Allocates a DMA-Buf with the specified size, reusing a retained buffer if possible
+    ///
+    /// # Errors
+    ///
+    /// Will return [Error] if no buffer could be reused and the allocation fails.
+    pub fn allocate(&self, len: usize) -> Result<DmaBuf> {
+        let Some(class) = Self::size_class(len) else {
+            // The allocator will reject it, and report the error
+            return self.allocator.allocate_with(len, &self.options);
+        };
+
+        {
+            let mut state = self.state();
+
+            if let Some((id, buffer)) = state.free.get_mut(&class).and_then(Vec::pop) {
+                state.retained -= buffer.len();
+                state.retained_ids.remove(&id);
+
+                debug!("Reusing buffer {buffer:?} for an allocation of {len} bytes");
+
+                return Ok(buffer);
+            }
+        }
+
+        self.allocate_new(class)
+    }
+
+    /// Allocates a buffer from the allocator, and records it as belonging to the pool
+    fn allocate_new(&self, len: usize) -> Result<DmaBuf> {
+        let buffer = self.allocator.allocate_with(len, &self.options)?;
+
+        if let Some(id) = buffer_id(&buffer) {
+            self.state().allocated.insert(id);
+        }
+
+        Ok(buffer)
+    }
+
+    /// Gives a DMA-Buf back to the pool
+    ///
+    /// The buffer must not be used anymore by the application, or shared with any other device
+    /// or process, since it will be handed back to the next allocation of the same size.
+    ///
+    /// Buffers that haven't been allocated by the pool, or that would make the pool exceed its
+    /// maximum size, are freed. Buffers are recognized through their inode, so any file
+    /// descriptor pointing to a buffer allocated by the pool is accepted, but a buffer already
+    /// retained by the pool is only retained once.
+    pub fn release(&self, buffer: DmaBuf) {
+        let id = buffer_id(&buffer);
+        let len = buffer.len();
+        let mut state = self.state();
+
+        let Some(id) = id.filter(|id| state.allocated.contains(id)) else {
+            debug!("Buffer {buffer:?} hasn't been allocated by the pool, freeing.");
+            return;
+        };
+
+        if state.retained_ids.contains(&id) {
+            debug!("Buffer {buffer:?} is already retained by the pool, dropping.");
+            return;
+        }
+
+        if state
+            .retained
+            .checked_add(len)
+            .is_none_or(|retained| retained > self.max_retained)
+        {
+            debug!("Pool is full, freeing buffer {buffer:?}");
+            state.allocated.remove(&id);
+            return;
+        }
+
+        state.retained += len;
+        state.retained_ids.insert(id);
+        state.free.entry(len).or_default().push((id, buffer));
+    }
+
+    /// Allocates `count` buffers of the specified size and puts them in the pool
+    ///
+    /// # Errors
+    ///
+    /// Will return [Error] if any of the allocations fails.
+    pub fn prewarm(&self, len: usize, count: usize) -> Result<()> {
+        let class = Self::size_class(len).unwrap_or(len);
+
+        for _ in 0..count {
+            let buffer = self.allocate_new(class)?;
+
+            self.release(buffer);
+        }
+
+        Ok(())
+    }
+
+    /// Frees all the buffers retained by the pool
+    ///
+    /// The pool also forgets about the buffers it allocated that aren't opened by the process
+    /// anymore, and thus can't be released.
+    pub fn trim(&self) {
+        let open = open_files();
+        let mut state = self.state();
+
+        debug!("Trimming pool, freeing {} bytes", state.retained);
+
+        let freed = core::mem::take(&mut state.free);
+        for (id, _) in freed.values().flatten() {
+            state.allocated.remove(id);
+        }
+
+        state.retained_ids.clear();
+        state.retained = 0;
+
+        if let Some(open) = open {
+            state.allocated.retain(|id| open.contains(id));
+        }
+    }
+}
+
+#[cfg(test)]
+#[cfg(feature = "mock")]
+mod tests {
+    use crate::{BufferPool, Heap, HeapKind, MockHeap};
+
+    #[test]
+    fn release_retains_own_buffers() {
+        let pool = BufferPool::new(Heap::mock(MockHeap::new(HeapKind::System)));
+
+        let buffer = pool.allocate(4096).unwrap();
+        pool.release(buffer);
+        assert_eq!(pool.retained_bytes(), 4096);
+
+        let _buffer = pool.allocate(4096).unwrap();
+        assert_eq!(pool.retained_bytes(), 0);
+    }
+
+    #[test]
+    fn release_frees_foreign_buffers() {
+        let pool = BufferPool::new(Heap::mock(MockHeap::new(HeapKind::System)));
+
+        // Same kind of Heap, but a different instance
+        let other = Heap::mock(MockHeap::new(HeapKind::System));
+        pool.release(other.allocate(4096).unwrap());
+        assert_eq!(pool.retained_bytes(), 0);
+    }
+
+    #[test]
+    fn release_retains_duplicated_buffers_once() {
+        let pool = BufferPool::new(Heap::mock(MockHeap::new(HeapKind::System)));
+
+        let buffer = pool.allocate(4096).unwrap();
+        pool.release(buffer.try_clone().unwrap());
+        assert_eq!(pool.retained_bytes(), 4096);
+
+        pool.release(buffer);
+        assert_eq!(pool.retained_bytes(), 4096);
+    }
+
+    #[test]
+    fn trim_forgets_buffers() {
+        let pool = BufferPool::new(Heap::mock(MockHeap::new(HeapKind::System)));
+
+        let buffer = pool.allocate(4096).unwrap();
+        let duplicate = buffer.try_clone().unwrap();
+        pool.release(buffer);
+        pool.trim();
+
+        pool.release(duplicate);
+        assert_eq!(pool.retained_bytes(), 0);
+    }
+
+    #[test]
+    fn trim_forgets_dropped_buffers() {
+        let pool = BufferPool::new(Heap::mock(MockHeap::new(HeapKind::System)));
+
+        let kept = pool.allocate(4096).unwrap();
+        drop(pool.allocate(4096).unwrap());
+        pool.trim();
+        assert_eq!(pool.state().allocated.len(), 1);
+
+        pool.release(kept);
+        assert_eq!(pool.retained_bytes(), 4096);
+    }
+}
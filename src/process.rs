@@ -0,0 +1,369 @@
+use alloc::collections::BTreeMap;
+use std::{
+    fs, io,
+    os::{fd::RawFd, unix::fs::MetadataExt},
+};
+
+use log::debug;
+
+use crate::{
+    fdinfo::{is_dma_buf_path, read_process_fdinfo},
+    DmaBufInfo, Result,
+};
+
+/// A DMA-Buf referenced by a process, either through a file descriptor or a memory mapping
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ProcessBuffer {
+    inode: u64,
+    size: usize,
+    exporter: Option<String>,
+    name: Option<String>,
+    fds: Vec<RawFd>,
+    mapped_size: usize,
+    processes: usize,
+}
+
+impl ProcessBuffer {
+    fn new(inode: u64) -> Self {
+        Self {
+            inode,
+            size: 0,
+            exporter: None,
+            name: None,
+            fds: Vec::new(),
+            mapped_size: 0,
+            processes: 1,
+        }
+    }
+
+    /// Returns the inode number of the buffer
+    #[must_use]
+    pub fn inode(&self) -> u64 {
+        self.inode
+    }
+
+    /// Returns the size of the buffer, in bytes
+    ///
+    /// If the process only maps the buffer without holding a file descriptor to it, the size of
+    /// the buffer isn't known and the mapped size is reported instead.
+    #[must_use]
+    pub fn size(&self) -> usize {
+        self.size
+    }
+
+    /// Returns the name of the exporter of the buffer, if known
+    #[must_use]
+    pub fn exporter(&self) -> Option<&str> {
+        self.exporter.as_deref()
+    }
+
+    /// Returns the debug name of the buffer, if any
+    #[must_use]
+    pub fn name(&self) -> Option<&str> {
+        self.name.as_deref()
+    }
+
+    /// Returns the file descriptors the process holds to the buffer
+    #[must_use]
+    pub fn fds(&self) -> &[RawFd] {
+        &self.fds
+    }
+
+    /// Returns the size of the buffer mapped by the process, in bytes
+    #[must_use]
+    pub fn mapped_size(&self) -> usize {
+        self.mapped_size
+    }
+
+    /// Returns the number of processes referencing the buffer
+    #[must_use]
+    pub fn processes(&self) -> usize {
+        self.processes
+    }
+
+    /// Returns the share of the buffer attributed to the process, in bytes
+    ///
+    /// The buffer size is split evenly across all the processes referencing it.
+    #[must_use]
+    pub fn proportional_size(&self) -> usize {
+        self.size / self.processes.max(1)
+    }
+}
+
+/// The DMA-Bufs referenced by a process
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ProcessUsage {
+    pid: u32,
+    comm: String,
+    buffers: Vec<ProcessBuffer>,
+}
+
+impl ProcessUsage {
+    /// Returns the process ID
+    #[must_use]
+    pub fn pid(&self) -> u32 {
+        self.pid
+    }
+
+    /// Returns the process command name
+    #[must_use]
+    pub fn comm(&self) -> &str {
+        &self.comm
+    }
+
+    /// Returns the DMA-Bufs referenced by the process, sorted by inode
+    #[must_use]
+    pub fn buffers(&self) -> &[ProcessBuffer] {
+        &self.buffers
+    }
+
+    /// Returns the total size of the DMA-Bufs referenced by the process, in bytes
+    #[must_use]
+    pub fn total_size(&self) -> usize {
+        self.buffers.iter().map(ProcessBuffer::size).sum()
+    }
+
+    /// Returns the share of the DMA-Bufs attributed to the process, in bytes
+    ///
+    /// Each buffer size is split evenly across all the processes referencing it.
+    #[must_use]
+    pub fn proportional_size(&self) -> usize {
+        self.buffers
+            .iter()
+            .map(ProcessBuffer::proportional_size)
+            .sum()
+    }
+}
+
+/// Attribution of the DMA-Bufs of the system to the processes referencing them
+///
+/// This is similar to Android's `dmabuf_dump`. Only the processes we're allowed to inspect are
+/// reported, so running as root is usually needed to get the whole picture.
+///
+/// # Example
+///
+/// ```no_run
+/// use dma_heap::DmaBufAttribution;
+///
+/// let attribution = DmaBufAttribution::collect().unwrap();
+///
+/// for process in attribution.processes() {
+///     println!(
+///         "{} ({}): {} bytes, {} bytes proportional",
+///         process.comm(),
+///         process.pid(),
+///         process.total_size(),
+///         process.proportional_size()
+///     );
+/// }
+/// ```
+#[derive(Clone, Debug)]
+pub struct DmaBufAttribution {
+    processes: Vec<ProcessUsage>,
+}
+
+impl DmaBufAttribution {
+    /// Walks `/proc` to find the DMA-Bufs referenced by each process
+    ///
+    /// # Errors
+    ///
+    /// Will return [Error] if `/proc` can't be read.
+    pub fn collect() -> Result<Self> {
+        let mut processes = Vec::new();
+
+        for entry in fs::read_dir("/proc")? {
+            let entry = entry?;
+            let Some(pid) = entry
+                .file_name()
+                .to_str()
+                .and_then(|name| name.parse().ok())
+            else {
+                continue;
+            };
+
+            match read_process(pid) {
+                Ok(process) if process.buffers.is_empty() => {}
+                Ok(process) => processes.push(process),
+                Err(err) => debug!("Skipping process {pid}: {err}"),
+            }
+        }
+
+        let mut holders = BTreeMap::<u64, usize>::new();
+        for buffer in processes.iter().flat_map(|process| &process.buffers) {
+            *holders.entry(buffer.inode).or_default() += 1;
+        }
+
+        for buffer in processes
+            .iter_mut()
+            .flat_map(|process| &mut process.buffers)
+        {
+            buffer.processes = holders.get(&buffer.inode).copied().unwrap_or(1);
+        }
+
+        processes.sort_by_key(|process| process.pid);
+
+        Ok(Self { processes })
+    }
+
+    /// Returns the processes referencing at least one DMA-Buf, sorted by PID
+    #[must_use]
+    pub fn processes(&self) -> &[ProcessUsage] {
+        &self.processes
+    }
+
+    /// Returns the processes referencing the DMA-Buf with the given inode number
+    pub fn holders(&self, inode: u64) -> impl Iterator<Item = &ProcessUsage> {
+        self.processes
+            .iter()
+            .filter(move |process| process.buffers.iter().any(|buffer| buffer.inode == inode))
+    }
+}
+
+/// Parses a line of `/proc/<pid>/maps`, returning the inode and size of the mapping if it's a
+/// DMA-Buf
+fn parse_maps_line(line: &str) -> Option<(u64, usize)> {
+    let mut fields = line.splitn(6, ' ');
+    let (Some(range), Some(_perms), Some(_offset), Some(_dev), Some(inode), Some(path)) = (
+        fields.next(),
+        fields.next(),
+        fields.next(),
+        fields.next(),
+        fields.next(),
+        fields.next(),
+    ) else {
+        return None;
+    };
+
+    if !is_dma_buf_path(path.trim()) {
+        return None;
+    }
+
+    let (start, end) = range.split_once('-')?;
+    let start = usize::from_str_radix(start, 16).ok()?;
+    let end = usize::from_str_radix(end, 16).ok()?;
+
+    Some((inode.parse().ok()?, end.saturating_sub(start)))
+}
+
+fn read_process(pid: u32) -> io::Result<ProcessUsage> {
+    let comm = fs::read_to_string(format!("/proc/{pid}/comm"))?
+        .trim()
+        .to_owned();
+
+    let mut buffers = BTreeMap::new();
+
+    for entry in fs::read_dir(format!("/proc/{pid}/fd"))? {
+        let entry = entry?;
+        let Some(fd) = entry
+            .file_name()
+            .to_str()
+            .and_then(|name| name.parse().ok())
+        else {
+            continue;
+        };
+
+        // The file descriptor might be closed while we're walking the directory
+        let Ok(target) = fs::read_link(entry.path()) else {
+            continue;
+        };
+
+        if !is_dma_buf_path(&target.to_string_lossy()) {
+            continue;
+        }
+
+        let Ok(inode) = fs::metadata(entry.path()).map(|metadata| metadata.ino()) else {
+            continue;
+        };
+
+        let buffer = buffers
+            .entry(inode)
+            .or_insert_with(|| ProcessBuffer::new(inode));
+        buffer.fds.push(fd);
+
+        if buffer.exporter.is_some() {
+            continue;
+        }
+
+        match read_process_fdinfo(&pid.to_string(), fd)
+            .map_err(Into::into)
+            .and_then(|fdinfo| DmaBufInfo::from_fdinfo(&fdinfo, inode))
+        {
+            Ok(info) => {
+                buffer.size = info.size();
+                buffer.exporter = Some(info.exporter().to_owned());
+                buffer.name = info.name().map(str::to_owned);
+            }
+            Err(err) => debug!("Couldn't read the fdinfo of {pid}:{fd}: {err}"),
+        }
+    }
+
+    for line in fs::read_to_string(format!("/proc/{pid}/maps"))?.lines() {
+        let Some((inode, size)) = parse_maps_line(line) else {
+            continue;
+        };
+
+        let buffer = buffers
+            .entry(inode)
+            .or_insert_with(|| ProcessBuffer::new(inode));
+        buffer.mapped_size += size;
+    }
+
+    let buffers = buffers
+        .into_values()
+        .map(|mut buffer| {
+            if buffer.exporter.is_none() {
+                buffer.size = buffer.size.max(buffer.mapped_size);
+            }
+
+            buffer
+        })
+        .collect();
+
+    Ok(ProcessUsage { pid, comm, buffers })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::parse_maps_line;
+
+    #[test]
+    fn maps_dma_buf() {
+        let line = "7f3a1c000000-7f3a1c800000 rw-s 00000000 00:0f 2062                       /dmabuf:camera-frame";
+
+        assert_eq!(parse_maps_line(line), Some((2062, 8 << 20)));
+    }
+
+    #[test]
+    fn maps_dma_buf_without_name() {
+        let line =
+            "7f3a1d000000-7f3a1d003000 r--s 00000000 00:0f 2144                       /dmabuf:";
+
+        assert_eq!(parse_maps_line(line), Some((2144, 12288)));
+    }
+
+    #[test]
+    fn maps_dma_buf_name_with_spaces() {
+        let line = "7f3a1d000000-7f3a1d001000 rw-s 00000000 00:0f 17                         /dmabuf:my frame";
+
+        assert_eq!(parse_maps_line(line), Some((17, 4096)));
+    }
+
+    #[test]
+    fn maps_old_kernel() {
+        let line = "7f3a1d000000-7f3a1d001000 rw-s 00000000 00:0a 9034                       anon_inode:dmabuf";
+
+        assert_eq!(parse_maps_line(line), Some((9034, 4096)));
+    }
+
+    #[test]
+    fn maps_other_mappings() {
+        for line in [
+            "55d0c4a00000-55d0c4a21000 rw-p 00000000 00:00 0                          [heap]",
+            "7f3a1e000000-7f3a1e200000 rw-p 00000000 00:00 0 ",
+            "7f3a1e200000-7f3a1e228000 r--p 00000000 fd:01 1573013                    /usr/lib/libc.so.6",
+            "7f3a1f000000-7f3a1f001000 rw-s 00000000 00:01 3072                       /memfd:dma-heap-mock (deleted)",
+        ] {
+            assert_eq!(parse_maps_line(line), None, "{line}");
+        }
+    }
+}
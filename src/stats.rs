@@ -0,0 +1,255 @@
+use alloc::collections::BTreeMap;
+use std::{fs, io, path::Path};
+
+use log::debug;
+
+use crate::Result;
+
+const SYSFS_BUFFERS_DIR: &str = "/sys/kernel/dmabuf/buffers";
+const DEBUGFS_BUFINFO_PATH: &str = "/sys/kernel/debug/dma_buf/bufinfo";
+
+/// A DMA-Buf, as reported by the system-wide accounting
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct BufferStats {
+    inode: u64,
+    size: usize,
+    exporter: String,
+}
+
+impl BufferStats {
+    /// Returns the inode number of the buffer
+    #[must_use]
+    pub fn inode(&self) -> u64 {
+        self.inode
+    }
+
+    /// Returns the size of the buffer, in bytes
+    #[must_use]
+    pub fn size(&self) -> usize {
+        self.size
+    }
+
+    /// Returns the name of the exporter of the buffer
+    #[must_use]
+    pub fn exporter(&self) -> &str {
+        &self.exporter
+    }
+}
+
+/// The DMA-Bufs allocated by a single exporter
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ExporterStats {
+    name: String,
+    count: usize,
+    size: usize,
+}
+
+impl ExporterStats {
+    /// Returns the name of the exporter
+    #[must_use]
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// Returns the number of buffers allocated by the exporter
+    #[must_use]
+    pub fn count(&self) -> usize {
+        self.count
+    }
+
+    /// Returns the total size of the buffers allocated by the exporter, in bytes
+    #[must_use]
+    pub fn size(&self) -> usize {
+        self.size
+    }
+}
+
+/// System-wide DMA-Buf accounting
+///
+/// # Example
+///
+/// ```no_run
+/// use dma_heap::DmaBufStats;
+///
+/// let stats = DmaBufStats::read().unwrap();
+///
+/// for exporter in stats.exporters() {
+///     println!(
+///         "{}: {} buffers, {} bytes",
+///         exporter.name(),
+///         exporter.count(),
+///         exporter.size()
+///     );
+/// }
+/// ```
+#[derive(Clone, Debug)]
+pub struct DmaBufStats {
+    buffers: Vec<BufferStats>,
+}
+
+impl DmaBufStats {
+    /// Reads the list of DMA-Bufs currently allocated in the system
+    ///
+    /// The list is retrieved from `/sys/kernel/dmabuf/buffers` if the kernel has been built with
+    /// `CONFIG_DMABUF_SYSFS_STATS`, and from `/sys/kernel/debug/dma_buf/bufinfo` otherwise. Both
+    /// usually require root privileges.
+    ///
+    /// # Errors
+    ///
+    /// Will return [Error] if neither source is available, or if they can't be read.
+    pub fn read() -> Result<Self> {
+        let buffers = match read_sysfs(Path::new(SYSFS_BUFFERS_DIR)) {
+            Err(err) if err.kind() == io::ErrorKind::NotFound => {
+                debug!("DMA-Buf sysfs stats not available, falling back to debugfs");
+
+                parse_bufinfo(&fs::read_to_string(DEBUGFS_BUFINFO_PATH)?)
+            }
+            res => res?,
+        };
+
+        Ok(Self { buffers })
+    }
+
+    /// Returns the DMA-Bufs currently allocated
+    #[must_use]
+    pub fn buffers(&self) -> &[BufferStats] {
+        &self.buffers
+    }
+
+    /// Returns the DMA-Bufs currently allocated, grouped by exporter and sorted by name
+    #[must_use]
+    pub fn exporters(&self) -> Vec<ExporterStats> {
+        let mut exporters = BTreeMap::new();
+
+        for buffer in &self.buffers {
+            let exporter = exporters
+                .entry(buffer.exporter.as_str())
+                .or_insert_with(|| ExporterStats {
+                    name: buffer.exporter.clone(),
+                    count: 0,
+                    size: 0,
+                });
+
+            exporter.count += 1;
+            exporter.size += buffer.size;
+        }
+
+        exporters.into_values().collect()
+    }
+
+    /// Returns the total size of the DMA-Bufs currently allocated, in bytes
+    #[must_use]
+    pub fn total_size(&self) -> usize {
+        self.buffers.iter().map(|buffer| buffer.size).sum()
+    }
+}
+
+fn read_sysfs(dir: &Path) -> io::Result<Vec<BufferStats>> {
+    let mut buffers = Vec::new();
+
+    for entry in fs::read_dir(dir)? {
+        let entry = entry?;
+        let Some(inode) = entry
+            .file_name()
+            .to_str()
+            .and_then(|name| name.parse().ok())
+        else {
+            continue;
+        };
+
+        let path = entry.path();
+
+        // The buffer might be freed while we're walking the directory
+        let (size, exporter) = match (
+            fs::read_to_string(path.join("size")),
+            fs::read_to_string(path.join("exporter_name")),
+        ) {
+            (Ok(size), Ok(exporter)) => (size, exporter),
+            (Err(err), _) | (_, Err(err)) if err.kind() == io::ErrorKind::NotFound => continue,
+            (Err(err), _) | (_, Err(err)) => return Err(err),
+        };
+
+        buffers.push(BufferStats {
+            inode,
+            size: size
+                .trim()
+                .parse()
+                .map_err(|_err| io::Error::from(io::ErrorKind::InvalidData))?,
+            exporter: exporter.trim().to_owned(),
+        });
+    }
+
+    buffers.sort_by_key(|buffer| buffer.inode);
+
+    Ok(buffers)
+}
+
+/// Parses the debugfs `bufinfo` file
+///
+/// Each buffer is reported on a line with the size, flags, mode, count, exporter name, inode and
+/// name, separated by tabs. The other lines (headers, attachments, fences, totals) are ignored.
+fn parse_bufinfo(content: &str) -> Vec<BufferStats> {
+    let mut buffers = content
+        .lines()
+        .filter_map(|line| {
+            let fields = line.split('\t').collect::<Vec<_>>();
+            let [size, _flags, _mode, _count, exporter, inode, ..] = fields.as_slice() else {
+                return None;
+            };
+
+            Some(BufferStats {
+                inode: inode.trim().parse().ok()?,
+                size: size.trim().parse().ok()?,
+                exporter: exporter.trim().to_owned(),
+            })
+        })
+        .collect::<Vec<_>>();
+
+    buffers.sort_by_key(|buffer| buffer.inode);
+    buffers
+}
+
+#[cfg(test)]
+mod tests {
+    use super::parse_bufinfo;
+
+    // Captured from /sys/kernel/debug/dma_buf/bufinfo on Linux 6.6
+    const BUFINFO: &str = "
+Dma-buf Objects:
+size    \tflags   \tmode    \tcount   \texp_name\tino     \tname
+00012288\t00000002\t00080007\t00000003\tsystem\t00002144\t<none>
+\tAttached Devices:
+\tfd00000.gpu
+Total 1 devices attached
+
+08388608\t00000002\t00080007\t00000002\tlinux,cma\t00002062\tcamera-frame
+\twrite fence:drm_sched gfx_0.0.0 seq 1204 signalled
+\tAttached Devices:
+Total 0 devices attached
+
+
+Total 2 objects, 8400896 bytes
+";
+
+    #[test]
+    fn bufinfo_buffers() {
+        let buffers = parse_bufinfo(BUFINFO);
+
+        assert_eq!(buffers.len(), 2);
+
+        assert_eq!(buffers[0].inode(), 2062);
+        assert_eq!(buffers[0].size(), 8 << 20);
+        assert_eq!(buffers[0].exporter(), "linux,cma");
+
+        assert_eq!(buffers[1].inode(), 2144);
+        assert_eq!(buffers[1].size(), 12288);
+        assert_eq!(buffers[1].exporter(), "system");
+    }
+
+    #[test]
+    fn bufinfo_empty() {
+        let buffers = parse_bufinfo("\nDma-buf Objects:\nsize    \tflags   \tmode    \tcount   \texp_name\tino     \tname\n\nTotal 0 objects, 0 bytes\n");
+
+        assert!(buffers.is_empty());
+    }
+}
@@ -0,0 +1,295 @@
+use core::time::Duration;
+use std::{
+    io,
+    os::fd::{AsFd, BorrowedFd, OwnedFd},
+    time::Instant,
+};
+
+use rustix::{
+    event::{poll, PollFd, PollFlags},
+    io::Errno,
+};
+#[cfg(feature = "tokio")]
+use tokio::io::{unix::AsyncFd, Interest};
+
+use crate::{
+    ioctl::{
+        sync_fence_info, sync_file_info, sync_file_merge, DMA_BUF_SYNC_READ, DMA_BUF_SYNC_RW,
+        DMA_BUF_SYNC_WRITE, SYNC_NAME_LEN,
+    },
+    Result,
+};
+
+/// Waits asynchronously for a file descriptor registered in the tokio reactor to report the given
+/// events
+///
+/// The reactor caches the readiness until it's cleared, so it's checked against `poll()` to catch
+/// the fences attached since the last wait.
+#[cfg(feature = "tokio")]
+pub(crate) async fn wait_for_interest(
+    fd: &AsyncFd<OwnedFd>,
+    interest: Interest,
+    events: PollFlags,
+) -> Result<()> {
+    loop {
+        let mut guard = fd.ready(interest).await?;
+
+        if wait_for_events(fd.get_ref().as_fd(), events, Some(Duration::ZERO))? {
+            return Ok(());
+        }
+
+        guard.clear_ready();
+    }
+}
+
+/// Waits for a file descriptor to report the given events, or for the timeout to expire
+///
+/// Returns true if the events have been reported, false if the timeout expired.
+pub(crate) fn wait_for_events(
+    fd: BorrowedFd<'_>,
+    events: PollFlags,
+    timeout: Option<Duration>,
+) -> Result<bool> {
+    // A timeout too large to be represented is as good as no timeout at all
+    let deadline = timeout.and_then(|timeout| Instant::now().checked_add(timeout));
+
+    loop {
+        let timeout_ms = match deadline {
+            None => -1,
+            Some(deadline) => {
+                let remaining = deadline.saturating_duration_since(Instant::now());
+
+                // Round up so that we don't spin for sub-millisecond timeouts
+                i32::try_from(remaining.as_nanos().div_ceil(1_000_000)).unwrap_or(i32::MAX)
+            }
+        };
+
+        let mut fds = [PollFd::from_borrowed_fd(fd, events)];
+        match poll(&mut fds, timeout_ms) {
+            Ok(0) => return Ok(false),
+            Ok(_) => return Ok(true),
+            Err(Errno::INTR | Errno::AGAIN) => {}
+            Err(err) => return Err(io::Error::from(err).into()),
+        }
+    }
+}
+
+fn c_string(bytes: &[u8]) -> String {
+    let len = bytes.iter().position(|b| *b == 0).unwrap_or(bytes.len());
+
+    String::from_utf8_lossy(&bytes[..len]).into_owned()
+}
+
+/// Status of a fence, or of a whole [`SyncFile`]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum FenceStatus {
+    /// The fence hasn't signaled yet
+    Active,
+
+    /// The fence has signaled
+    Signaled,
+
+    /// The fence has signaled with an error
+    Error(i32),
+}
+
+impl From<i32> for FenceStatus {
+    fn from(status: i32) -> Self {
+        match status {
+            0 => Self::Active,
+            1.. => Self::Signaled,
+            _ => Self::Error(-status),
+        }
+    }
+}
+
+/// Information about one of the fences of a [`SyncFile`]
+#[derive(Clone, Debug)]
+pub struct FenceInfo {
+    timeline: String,
+    driver: String,
+    status: FenceStatus,
+    timestamp: Duration,
+}
+
+impl FenceInfo {
+    /// Returns the name of the timeline the fence belongs to
+    #[must_use]
+    pub fn timeline(&self) -> &str {
+        &self.timeline
+    }
+
+    /// Returns the name of the driver that created the fence
+    #[must_use]
+    pub fn driver(&self) -> &str {
+        &self.driver
+    }
+
+    /// Returns the fence status
+    #[must_use]
+    pub fn status(&self) -> FenceStatus {
+        self.status
+    }
+
+    /// Returns the time the fence signaled at, in `CLOCK_MONOTONIC`
+    ///
+    /// It's only relevant if the fence has signaled.
+    #[must_use]
+    pub fn timestamp(&self) -> Duration {
+        self.timestamp
+    }
+}
+
+impl From<&sync_fence_info> for FenceInfo {
+    fn from(info: &sync_fence_info) -> Self {
+        Self {
+            timeline: c_string(&info.obj_name),
+            driver: c_string(&info.driver_name),
+            status: FenceStatus::from(info.status),
+            timestamp: Duration::from_nanos(info.timestamp_ns),
+        }
+    }
+}
+
+/// Information about a [`SyncFile`] and its fences
+#[derive(Clone, Debug)]
+pub struct SyncFileInfo {
+    name: String,
+    status: FenceStatus,
+    fences: Vec<FenceInfo>,
+}
+
+impl SyncFileInfo {
+    /// Returns the name of the sync file
+    #[must_use]
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// Returns the status of the sync file
+    ///
+    /// The sync file has signaled once all its fences have.
+    #[must_use]
+    pub fn status(&self) -> FenceStatus {
+        self.status
+    }
+
+    /// Returns the fences of the sync file
+    #[must_use]
+    pub fn fences(&self) -> &[FenceInfo] {
+        &self.fences
+    }
+}
+
+/// Kind of access the fences of a [`SyncFile`] relate to
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum SyncAccess {
+    /// Read access to the buffer
+    Read,
+
+    /// Write access to the buffer
+    Write,
+
+    /// Read and Write access to the buffer
+    ReadWrite,
+}
+
+impl SyncAccess {
+    pub(crate) fn flags(self) -> u32 {
+        match self {
+            Self::Read => DMA_BUF_SYNC_READ,
+            Self::Write => DMA_BUF_SYNC_WRITE,
+            Self::ReadWrite => DMA_BUF_SYNC_RW,
+        }
+    }
+
+    fn union(self, other: Self) -> Self {
+        if self == other {
+            self
+        } else {
+            Self::ReadWrite
+        }
+    }
+}
+
+/// A Linux `sync_file`, holding a set of fences
+///
+/// It can be exported from a [`crate::DmaBuf`] using [`crate::DmaBuf::export_sync_file`], and
+/// imported into one using [`crate::DmaBuf::import_sync_file`].
+#[derive(Debug)]
+pub struct SyncFile {
+    fd: OwnedFd,
+    access: SyncAccess,
+}
+
+impl SyncFile {
+    /// Creates a [`SyncFile`] from a file descriptor and the access its fences relate to
+    ///
+    /// The file descriptor isn't checked.
+    #[must_use]
+    pub fn new(fd: OwnedFd, access: SyncAccess) -> Self {
+        Self { fd, access }
+    }
+
+    /// Returns the access the fences relate to
+    #[must_use]
+    pub fn access(&self) -> SyncAccess {
+        self.access
+    }
+
+    /// Creates a new [`SyncFile`] holding the fences of both sync files
+    ///
+    /// The name will be truncated to 31 bytes.
+    ///
+    /// # Errors
+    ///
+    /// Will return [Error] if the `SYNC_IOC_MERGE` ioctl fails.
+    pub fn merge(&self, other: &Self, name: &str) -> Result<Self> {
+        let mut raw_name = [0; SYNC_NAME_LEN];
+        let len = name.len().min(SYNC_NAME_LEN - 1);
+        raw_name[..len].copy_from_slice(&name.as_bytes()[..len]);
+
+        let fd = sync_file_merge(self.fd.as_fd(), other.fd.as_fd(), raw_name)?;
+
+        Ok(Self::new(fd, self.access.union(other.access)))
+    }
+
+    /// Retrieves the status of the sync file and of its fences
+    ///
+    /// # Errors
+    ///
+    /// Will return [Error] if the `SYNC_IOC_FILE_INFO` ioctl fails.
+    pub fn info(&self) -> Result<SyncFileInfo> {
+        let (info, fences) = sync_file_info(self.fd.as_fd())?;
+
+        Ok(SyncFileInfo {
+            name: c_string(&info.name),
+            status: FenceStatus::from(info.status),
+            fences: fences.iter().map(FenceInfo::from).collect(),
+        })
+    }
+
+    /// Waits for all the fences to signal
+    ///
+    /// If `timeout` is `None`, this will wait forever. Returns true if the fences have signaled,
+    /// and false if the timeout expired.
+    ///
+    /// # Errors
+    ///
+    /// Will return [Error] if the `poll()` call fails.
+    pub fn wait(&self, timeout: Option<Duration>) -> Result<bool> {
+        wait_for_events(self.fd.as_fd(), PollFlags::IN, timeout)
+    }
+}
+
+impl AsFd for SyncFile {
+    fn as_fd(&self) -> BorrowedFd<'_> {
+        self.fd.as_fd()
+    }
+}
+
+impl From<SyncFile> for OwnedFd {
+    fn from(sync_file: SyncFile) -> Self {
+        sync_file.fd
+    }
+}
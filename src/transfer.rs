@@ -0,0 +1,441 @@
+#[cfg(all(test, feature = "mock"))]
+use core::cell::Cell;
+use std::{
+    ffi::OsStr,
+    io::{self, IoSlice, IoSliceMut},
+    os::{
+        fd::{AsFd, BorrowedFd, OwnedFd},
+        unix::ffi::OsStrExt,
+    },
+    path::PathBuf,
+};
+
+use rustix::{
+    cmsg_space,
+    io::Errno,
+    net::{
+        recvmsg, send, sendmsg, RecvAncillaryBuffer, RecvAncillaryMessage, RecvFlags,
+        SendAncillaryBuffer, SendAncillaryMessage, SendFlags,
+    },
+};
+
+#[cfg(all(test, feature = "mock"))]
+use crate::buffer::dma_buf_size;
+use crate::{DmaBuf, HeapKind, Result};
+
+/// Maximum number of file descriptors sent or received in a single message
+pub(crate) const MAX_FDS: usize = 16;
+
+/// `MSG_CTRUNC`, not exposed by rustix
+const MSG_CTRUNC: u32 = 0x8;
+
+/// Identifies the start of a buffer message, and the version of its format
+const MESSAGE_MAGIC: [u8; 4] = *b"DHB1";
+
+/// Length of the message header: the magic, followed by the length of the payload
+const HEADER_LEN: usize = 8;
+
+/// Maximum length of a message payload, the buffer descriptors and the metadata
+const MAX_PAYLOAD_LEN: usize = 64 << 10;
+
+const HEAP_NONE: u8 = 0;
+const HEAP_CMA: u8 = 1;
+const HEAP_SYSTEM: u8 = 2;
+const HEAP_CUSTOM: u8 = 3;
+
+#[cfg(all(test, feature = "mock"))]
+thread_local! {
+    // Whether the buffers received by the current thread are imported as mock buffers, since the
+    // memfds of the mock Heap aren't DMA-Bufs.
+    static ACCEPT_MOCK_BUFFERS: Cell<bool> = const { Cell::new(false) };
+}
+
+/// Lets the current thread receive the buffers of a [`crate::MockHeap`]
+#[cfg(all(test, feature = "mock"))]
+pub(crate) fn accept_mock_buffers() {
+    ACCEPT_MOCK_BUFFERS.set(true);
+}
+
+/// Sends data over a Unix socket, along with file descriptors
+///
+/// The file descriptors are attached to the first byte of data, which must thus not be empty.
+pub(crate) fn send_with_fds(
+    socket: BorrowedFd<'_>,
+    data: &[u8],
+    fds: &[BorrowedFd<'_>],
+) -> io::Result<()> {
+    if data.is_empty() || fds.len() > MAX_FDS {
+        return Err(io::Error::from(io::ErrorKind::InvalidInput));
+    }
+
+    let mut space = [0; cmsg_space!(ScmRights(MAX_FDS))];
+    let mut control = SendAncillaryBuffer::new(&mut space);
+    if !fds.is_empty() && !control.push(SendAncillaryMessage::ScmRights(fds)) {
+        return Err(io::Error::from(io::ErrorKind::InvalidInput));
+    }
+
+    let mut sent = loop {
+        match sendmsg(
+            socket,
+            &[IoSlice::new(data)],
+            &mut control,
+            SendFlags::NOSIGNAL,
+        ) {
+            Ok(sent) => break sent,
+            Err(Errno::INTR) => {}
+            Err(err) => return Err(err.into()),
+        }
+    };
+
+    // The file descriptors have been sent along with the first chunk, the rest is plain data.
+    while sent < data.len() {
+        match send(socket, &data[sent..], SendFlags::NOSIGNAL) {
+            Ok(len) => sent += len,
+            Err(Errno::INTR) => {}
+            Err(err) => return Err(err.into()),
+        }
+    }
+
+    Ok(())
+}
+
+/// Receives data from a Unix socket, along with the file descriptors attached to it
+///
+/// The file descriptors received are appended to `fds`, even if an error is returned, so that
+/// the caller owns, and eventually closes, them. Returns the number of bytes received, 0 meaning
+/// that the peer closed the connection.
+pub(crate) fn recv_with_fds(
+    socket: BorrowedFd<'_>,
+    buf: &mut [u8],
+    fds: &mut Vec<OwnedFd>,
+) -> io::Result<usize> {
+    let mut space = [0; cmsg_space!(ScmRights(MAX_FDS))];
+    let mut control = RecvAncillaryBuffer::new(&mut space);
+
+    let msg = loop {
+        match recvmsg(
+            socket,
+            &mut [IoSliceMut::new(buf)],
+            &mut control,
+            RecvFlags::CMSG_CLOEXEC,
+        ) {
+            Ok(msg) => break msg,
+            Err(Errno::INTR) => {}
+            Err(err) => return Err(err.into()),
+        }
+    };
+
+    for message in control.drain() {
+        if let RecvAncillaryMessage::ScmRights(received) = message {
+            fds.extend(received);
+        }
+    }
+
+    // Some file descriptors have been dropped by the kernel, we can't trust the message anymore.
+    if msg.flags.bits() & MSG_CTRUNC != 0 {
+        return Err(io::Error::from(io::ErrorKind::InvalidData));
+    }
+
+    Ok(msg.bytes)
+}
+
+/// Receives exactly `buf.len()` bytes, collecting the file descriptors attached to them
+fn recv_exact(socket: BorrowedFd<'_>, buf: &mut [u8], fds: &mut Vec<OwnedFd>) -> io::Result<()> {
+    let mut received = 0;
+
+    while received < buf.len() {
+        let len = recv_with_fds(socket, &mut buf[received..], fds)?;
+        if len == 0 {
+            return Err(io::Error::from(io::ErrorKind::UnexpectedEof));
+        }
+
+        received += len;
+    }
+
+    Ok(())
+}
+
+/// A cursor over a message payload
+struct Reader<'a> {
+    data: &'a [u8],
+}
+
+impl<'a> Reader<'a> {
+    fn take(&mut self, len: usize) -> io::Result<&'a [u8]> {
+        if len > self.data.len() {
+            return Err(io::Error::from(io::ErrorKind::InvalidData));
+        }
+
+        let (head, tail) = self.data.split_at(len);
+        self.data = tail;
+
+        Ok(head)
+    }
+
+    fn array<const N: usize>(&mut self) -> io::Result<[u8; N]> {
+        let mut array = [0; N];
+        array.copy_from_slice(self.take(N)?);
+
+        Ok(array)
+    }
+}
+
+fn encode_heap(payload: &mut Vec<u8>, heap: Option<&HeapKind>) -> io::Result<()> {
+    match heap {
+        None => payload.push(HEAP_NONE),
+        Some(HeapKind::Cma) => payload.push(HEAP_CMA),
+        Some(HeapKind::System) => payload.push(HEAP_SYSTEM),
+        Some(HeapKind::Custom(path)) => {
+            let path = path.as_os_str().as_bytes();
+            let len = u16::try_from(path.len())
+                .map_err(|_err| io::Error::from(io::ErrorKind::InvalidInput))?;
+
+            payload.push(HEAP_CUSTOM);
+            payload.extend_from_slice(&len.to_le_bytes());
+            payload.extend_from_slice(path);
+        }
+    }
+
+    Ok(())
+}
+
+fn decode_heap(reader: &mut Reader<'_>) -> io::Result<Option<HeapKind>> {
+    let [tag] = reader.array()?;
+
+    match tag {
+        HEAP_NONE => Ok(None),
+        HEAP_CMA => Ok(Some(HeapKind::Cma)),
+        HEAP_SYSTEM => Ok(Some(HeapKind::System)),
+        HEAP_CUSTOM => {
+            let len = u16::from_le_bytes(reader.array()?);
+            let path = OsStr::from_bytes(reader.take(len.into())?);
+
+            Ok(Some(HeapKind::Custom(PathBuf::from(path))))
+        }
+        _ => Err(io::Error::from(io::ErrorKind::InvalidData)),
+    }
+}
+
+/// Checks that a received file descriptor is a DMA-Buf, and wraps it
+fn import_buffer(fd: OwnedFd) -> Result<DmaBuf> {
+    #[cfg(all(test, feature = "mock"))]
+    if ACCEPT_MOCK_BUFFERS.get() {
+        let len = dma_buf_size(fd.as_fd())?;
+
+        return Ok(DmaBuf::new(fd, len, None).mocked());
+    }
+
+    DmaBuf::import(fd)
+}
+
+/// A set of DMA-Bufs received from a Unix socket, along with the metadata sent with them
+#[derive(Debug)]
+pub struct BufferMessage {
+    buffers: Vec<DmaBuf>,
+    heaps: Vec<Option<HeapKind>>,
+    metadata: Vec<u8>,
+}
+
+impl BufferMessage {
+    /// Returns the buffers, in the order they were sent
+    ///
+    /// The buffers are checked to be DMA-Bufs, but the Heap they have been allocated from isn't
+    /// known, see [`BufferMessage::claimed_heaps`].
+    #[must_use]
+    pub fn buffers(&self) -> &[DmaBuf] {
+        &self.buffers
+    }
+
+    /// Returns the Heap each buffer has been allocated from, according to the sender
+    ///
+    /// Nothing guarantees that the sender told the truth, so this must not be relied upon for
+    /// anything but informational purposes.
+    #[must_use]
+    pub fn claimed_heaps(&self) -> &[Option<HeapKind>] {
+        &self.heaps
+    }
+
+    /// Returns the metadata sent along with the buffers
+    #[must_use]
+    pub fn metadata(&self) -> &[u8] {
+        &self.metadata
+    }
+
+    /// Consumes the message, returning its buffers and metadata
+    #[must_use]
+    pub fn into_parts(self) -> (Vec<DmaBuf>, Vec<u8>) {
+        (self.buffers, self.metadata)
+    }
+}
+
+/// Sends DMA-Bufs over a Unix stream socket, along with their description and some metadata
+///
+/// The size and Heap of each buffer are sent along with its file descriptor, and the metadata is
+/// an opaque blob, left to the application to define, to carry the format, name or any other
+/// information about the buffers. Up to 16 buffers can be sent in a single message, and
+/// [`recv_buffers`] must be used on the other end.
+///
+/// # Example
+///
+/// ```no_run
+/// use std::os::unix::net::UnixStream;
+///
+/// use dma_heap::{recv_buffers, send_buffers, Heap, HeapKind};
+///
+/// let (sender, receiver) = UnixStream::pair().unwrap();
+///
+/// let heap = Heap::new(HeapKind::System).unwrap();
+/// let buffer = heap.allocate(1920 * 1080 * 4).unwrap();
+/// send_buffers(&sender, &[&buffer], b"XR24 1920x1080").unwrap();
+///
+/// let message = recv_buffers(&receiver).unwrap();
+/// assert_eq!(message.buffers()[0].len(), buffer.len());
+/// assert_eq!(message.metadata(), b"XR24 1920x1080");
+/// ```
+///
+/// # Errors
+///
+/// Will return [Error] if there are too many buffers, if the metadata is too large, or if the
+/// socket fails.
+pub fn send_buffers<S: AsFd>(socket: S, buffers: &[&DmaBuf], metadata: &[u8]) -> Result<()> {
+    let count = u8::try_from(buffers.len())
+        .ok()
+        .filter(|_count| buffers.len() <= MAX_FDS)
+        .ok_or_else(|| io::Error::from(io::ErrorKind::InvalidInput))?;
+
+    let mut payload = vec![count];
+    for buffer in buffers {
+        payload.extend_from_slice(&(buffer.len() as u64).to_le_bytes());
+        encode_heap(&mut payload, buffer.heap())?;
+    }
+    payload.extend_from_slice(metadata);
+
+    let len = u32::try_from(payload.len())
+        .ok()
+        .filter(|_len| payload.len() <= MAX_PAYLOAD_LEN)
+        .ok_or_else(|| io::Error::from(io::ErrorKind::InvalidInput))?;
+
+    let mut message = Vec::with_capacity(HEADER_LEN + payload.len());
+    message.extend_from_slice(&MESSAGE_MAGIC);
+    message.extend_from_slice(&len.to_le_bytes());
+    message.extend_from_slice(&payload);
+
+    let fds = buffers
+        .iter()
+        .map(|buffer| buffer.as_fd())
+        .collect::<Vec<_>>();
+
+    Ok(send_with_fds(socket.as_fd(), &message, &fds)?)
+}
+
+/// Receives DMA-Bufs sent with [`send_buffers`] from a Unix stream socket
+///
+/// It waits until the whole message has been received, and never reads past its end. The
+/// received file descriptors are closed if the message turns out to be invalid.
+///
+/// # Errors
+///
+/// Will return [`crate::HeapError::NotADmaBuf`] if one of the file descriptors isn't a DMA-Buf,
+/// or [Error] if the peer closed the connection, if the message is invalid, if some file
+/// descriptors have been dropped by the kernel, or if the buffer sizes don't match their
+/// description.
+pub fn recv_buffers<S: AsFd>(socket: S) -> Result<BufferMessage> {
+    let socket = socket.as_fd();
+    let invalid = || io::Error::from(io::ErrorKind::InvalidData);
+
+    // The file descriptors are dropped, and thus closed, on all the error paths.
+    let mut fds = Vec::new();
+
+    let mut header = [0; HEADER_LEN];
+    recv_exact(socket, &mut header, &mut fds)?;
+
+    let (magic, len) = header.split_at(MESSAGE_MAGIC.len());
+    if magic != MESSAGE_MAGIC {
+        return Err(invalid().into());
+    }
+
+    let len = u32::from_le_bytes(len.try_into().map_err(|_err| invalid())?) as usize;
+    if len > MAX_PAYLOAD_LEN {
+        return Err(invalid().into());
+    }
+
+    let mut payload = vec![0; len];
+    recv_exact(socket, &mut payload, &mut fds)?;
+
+    let mut reader = Reader { data: &payload };
+    let [count] = reader.array()?;
+    if usize::from(count) != fds.len() {
+        return Err(invalid().into());
+    }
+
+    let mut buffers = Vec::with_capacity(fds.len());
+    let mut heaps = Vec::with_capacity(fds.len());
+    for fd in fds {
+        let size =
+            usize::try_from(u64::from_le_bytes(reader.array()?)).map_err(|_err| invalid())?;
+
+        // The sender could lie about the Heap, so it isn't recorded as the buffer's
+        heaps.push(decode_heap(&mut reader)?);
+
+        let buffer = import_buffer(fd)?;
+        if buffer.len() != size {
+            return Err(invalid().into());
+        }
+
+        buffers.push(buffer);
+    }
+
+    Ok(BufferMessage {
+        buffers,
+        heaps,
+        metadata: reader.data.to_vec(),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use std::os::unix::net::UnixStream;
+
+    use rustix::fs::{ftruncate, memfd_create, MemfdFlags};
+
+    #[cfg(feature = "mock")]
+    use super::accept_mock_buffers;
+    use crate::{recv_buffers, send_buffers, DmaBuf, HeapError};
+
+    #[test]
+    fn reject_foreign_files() {
+        let (sender, receiver) = UnixStream::pair().unwrap();
+
+        let memfd = memfd_create("dma-heap-test", MemfdFlags::CLOEXEC).unwrap();
+        ftruncate(&memfd, 4096).unwrap();
+
+        // The conversion doesn't check anything, but the receiving end must
+        let buffer = DmaBuf::from(memfd);
+        send_buffers(&sender, &[&buffer], b"").unwrap();
+
+        let err = recv_buffers(&receiver).unwrap_err();
+        assert!(
+            matches!(err, HeapError::NotADmaBuf),
+            "A memfd was accepted as a DMA-Buf"
+        );
+    }
+
+    #[cfg(feature = "mock")]
+    #[test]
+    fn heaps_are_only_claimed() {
+        use crate::{Heap, HeapKind, MockHeap};
+
+        let (sender, receiver) = UnixStream::pair().unwrap();
+
+        let heap = Heap::mock(MockHeap::new(HeapKind::Cma));
+        let buffer = heap.allocate(4096).unwrap();
+        send_buffers(&sender, &[&buffer], b"frame").unwrap();
+
+        accept_mock_buffers();
+        let message = recv_buffers(&receiver).unwrap();
+        assert_eq!(message.buffers()[0].len(), 4096);
+        assert_eq!(message.buffers()[0].heap(), None);
+        assert_eq!(message.claimed_heaps(), [Some(HeapKind::Cma)]);
+        assert_eq!(message.metadata(), b"frame");
+    }
+}
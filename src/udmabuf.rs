@@ -0,0 +1,226 @@
+use std::{
+    fs::File,
+    io,
+    os::fd::{AsFd, BorrowedFd},
+    path::PathBuf,
+};
+
+use log::debug;
+use rustix::{
+    fs::{fcntl_add_seals, ftruncate, memfd_create, MemfdFlags, SealFlags},
+    io::Errno,
+    param::page_size,
+};
+
+use crate::{
+    buffer::dma_buf_name,
+    ioctl::{udmabuf_create, udmabuf_create_list},
+    open_error, AllocOptions, BufferAccess, DmaBuf, DmaBufAllocator, HeapError, HeapKind, Result,
+};
+
+const UDMABUF_PATH: &str = "/dev/udmabuf";
+
+/// A region of a memfd to turn into a DMA-Buf
+///
+/// The memfd must have been sealed with `F_SEAL_SHRINK`, and must not be sealed with
+/// `F_SEAL_WRITE`. The offset and size must be aligned on the page size.
+#[derive(Clone, Copy, Debug)]
+pub struct UdmabufRegion<'a> {
+    memfd: BorrowedFd<'a>,
+    offset: u64,
+    size: u64,
+}
+
+impl<'a> UdmabufRegion<'a> {
+    /// Creates a region of `size` bytes, starting at `offset` in the memfd
+    #[must_use]
+    pub fn new(memfd: BorrowedFd<'a>, offset: u64, size: u64) -> Self {
+        Self {
+            memfd,
+            offset,
+            size,
+        }
+    }
+
+    fn validate(&self) -> Result<()> {
+        let page_size = page_size() as u64;
+
+        if self.size == 0
+            || !self.size.is_multiple_of(page_size)
+            || !self.offset.is_multiple_of(page_size)
+        {
+            return Err(HeapError::InvalidAllocation {
+                path: PathBuf::from(UDMABUF_PATH),
+                len: usize::try_from(self.size).unwrap_or(usize::MAX),
+                errno: Errno::INVAL.raw_os_error(),
+            });
+        }
+
+        Ok(())
+    }
+}
+
+/// An allocator turning memfd memory into DMA-Bufs through `/dev/udmabuf`
+///
+/// # Example
+///
+/// ```no_run
+/// use dma_heap::Udmabuf;
+///
+/// let udmabuf = Udmabuf::new().unwrap();
+/// let buffer = udmabuf.allocate(4096).unwrap();
+///
+/// assert!(buffer.memfd().is_some());
+/// ```
+#[derive(Debug)]
+pub struct Udmabuf {
+    file: File,
+}
+
+#[allow(clippy::same_name_method)]
+impl Udmabuf {
+    /// Opens the udmabuf device
+    ///
+    /// # Errors
+    ///
+    /// Will return [Error] if the udmabuf device is not found in the system, or if the open call
+    /// fails.
+    pub fn new() -> Result<Self> {
+        let path = PathBuf::from(UDMABUF_PATH);
+
+        let file = File::open(&path)
+            .map_err(|err| open_error(&err, &HeapKind::Custom(path.clone()), &path))?;
+
+        Ok(Self { file })
+    }
+
+    /// Allocates a DMA-Buf backed by a new memfd with the specified size
+    ///
+    /// The memfd is available through [`DmaBuf::memfd`].
+    ///
+    /// # Errors
+    ///
+    /// Will return [Error] if the memfd creation or the underlying ioctl fails.
+    pub fn allocate(&self, len: usize) -> Result<DmaBuf> {
+        self.allocate_with(len, &AllocOptions::default())
+    }
+
+    /// Allocates a DMA-Buf backed by a new memfd with the specified size and options
+    ///
+    /// udmabuf doesn't support read-only buffers or any heap flag.
+    ///
+    /// # Errors
+    ///
+    /// Will return [Error] if the options aren't supported, or if the memfd creation or the
+    /// underlying ioctl fails.
+    pub fn allocate_with(&self, len: usize, options: &AllocOptions) -> Result<DmaBuf> {
+        if options.buffer_access() != BufferAccess::ReadWrite {
+            return Err(io::Error::from(io::ErrorKind::Unsupported).into());
+        }
+
+        if options.raw_heap_flags() != 0 {
+            return Err(HeapError::InvalidHeapFlags {
+                path: PathBuf::from(UDMABUF_PATH),
+                flags: options.raw_heap_flags(),
+                errno: Errno::INVAL.raw_os_error(),
+            });
+        }
+
+        if let Some(name) = options.buffer_name() {
+            dma_buf_name(name)?;
+        }
+
+        let size = len
+            .checked_next_multiple_of(page_size())
+            .filter(|size| *size != 0)
+            .ok_or_else(|| HeapError::InvalidAllocation {
+                path: PathBuf::from(UDMABUF_PATH),
+                len,
+                errno: Errno::INVAL.raw_os_error(),
+            })?;
+
+        debug!("Allocating udmabuf Buffer of size {size}");
+
+        let memfd = memfd_create(
+            "dma-heap-udmabuf",
+            MemfdFlags::CLOEXEC | MemfdFlags::ALLOW_SEALING,
+        )
+        .map_err(io::Error::from)?;
+
+        ftruncate(&memfd, size as u64).map_err(io::Error::from)?;
+
+        // udmabuf requires the memfd to be sealed against shrinking
+        fcntl_add_seals(&memfd, SealFlags::SHRINK).map_err(io::Error::from)?;
+
+        let fd = udmabuf_create(
+            self.file.as_fd(),
+            memfd.as_fd(),
+            0,
+            size as u64,
+            options.is_cloexec(),
+        )?;
+
+        debug!("Allocation succeeded, Buffer File Descriptor {fd:?}");
+
+        let buffer = DmaBuf::new(fd, size, None).with_memfd(memfd);
+
+        if let Some(name) = options.buffer_name() {
+            buffer.set_name(name)?;
+        }
+
+        Ok(buffer)
+    }
+
+    /// Creates a DMA-Buf out of a region of an existing memfd
+    ///
+    /// A duplicate of the memfd is available through [`DmaBuf::memfd`].
+    ///
+    /// # Errors
+    ///
+    /// Will return [Error] if the region is invalid, or if the underlying ioctl fails.
+    pub fn create(&self, region: &UdmabufRegion<'_>) -> Result<DmaBuf> {
+        region.validate()?;
+
+        let fd = udmabuf_create(
+            self.file.as_fd(),
+            region.memfd,
+            region.offset,
+            region.size,
+            true,
+        )?;
+
+        let memfd = region.memfd.try_clone_to_owned()?;
+
+        Ok(DmaBuf::from(fd).with_memfd(memfd))
+    }
+
+    /// Creates a DMA-Buf out of several memfd regions, concatenated in order
+    ///
+    /// # Errors
+    ///
+    /// Will return [Error] if any of the regions is invalid, or if the underlying ioctl fails.
+    pub fn create_list(&self, regions: &[UdmabufRegion<'_>]) -> Result<DmaBuf> {
+        let items = regions
+            .iter()
+            .map(|region| {
+                region.validate()?;
+
+                Ok((region.memfd, region.offset, region.size))
+            })
+            .collect::<Result<Vec<_>>>()?;
+
+        let fd = udmabuf_create_list(self.file.as_fd(), &items, true)?;
+
+        Ok(DmaBuf::from(fd))
+    }
+}
+
+impl DmaBufAllocator for Udmabuf {
+    fn allocate_with(&self, len: usize, options: &AllocOptions) -> Result<DmaBuf> {
+        self.allocate_with(len, options)
+    }
+
+    fn allocate(&self, len: usize) -> Result<DmaBuf> {
+        self.allocate(len)
+    }
+}